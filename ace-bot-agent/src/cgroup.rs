@@ -0,0 +1,154 @@
+//! Cgroups of commands, created below the cgroup delegated to the agent
+
+use ace_bot_agent::protocol::Limits;
+use rustix::fs::{Mode, OFlags};
+use rustix::io::Errno;
+use std::fs;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Period of `cpu.max` in microseconds
+const CPU_PERIOD: u64 = 100_000;
+const REMOVE_ATTEMPTS: usize = 100;
+const REMOVE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The cgroup of the agent, which needs to be delegated, e.g. by `Delegate=yes`
+#[derive(Debug)]
+pub struct Cgroups {
+    root: PathBuf,
+    next: AtomicU64,
+}
+
+impl Cgroups {
+    /// Moves the agent into a leaf of its cgroup so that controllers can be
+    /// enabled for the cgroups of commands
+    pub fn init() -> Result<Self, io::Error> {
+        let own = fs::read_to_string("/proc/self/cgroup")?;
+        let path = own
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| io::Error::other("not on the unified cgroup hierarchy"))?;
+        let root = Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'));
+        // the unified hierarchy may also be mounted elsewhere on hybrid systems
+        if !root.join("cgroup.controllers").exists() {
+            return Err(io::Error::other(format!(
+                "{} is not a cgroup2 mount",
+                root.display()
+            )));
+        }
+        let agent = root.join("agent");
+        create_dir(&agent)?;
+        fs::write(agent.join("cgroup.procs"), "0")?;
+        fs::write(root.join("cgroup.subtree_control"), "+memory +cpu +pids")?;
+        Ok(Self {
+            root,
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Creates a cgroup enforcing `limits`
+    pub fn create(&self, limits: &Limits) -> Result<Cgroup, io::Error> {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let path = self.root.join(format!("command-{id}"));
+        create_dir(&path)?;
+        if let Some(bytes) = limits.memory_max {
+            fs::write(path.join("memory.max"), bytes.to_string())?;
+        }
+        if let Some(percent) = limits.cpu_quota {
+            let quota = percent * CPU_PERIOD / 100;
+            fs::write(path.join("cpu.max"), format!("{quota} {CPU_PERIOD}"))?;
+        }
+        if let Some(tasks) = limits.tasks_max {
+            fs::write(path.join("pids.max"), tasks.to_string())?;
+        }
+        let procs = rustix::fs::open(
+            path.join("cgroup.procs"),
+            OFlags::WRONLY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(Cgroup { path, procs })
+    }
+}
+
+fn create_dir(path: &Path) -> Result<(), io::Error> {
+    match fs::create_dir(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    procs: OwnedFd,
+}
+
+impl Cgroup {
+    /// Returns a function moving the calling process into the cgroup
+    ///
+    /// The function only makes a system call, so it may run before `exec`.
+    pub fn enter(&self) -> Result<impl Fn() -> io::Result<()> + Send + Sync + 'static, io::Error> {
+        let procs = self.procs.try_clone()?;
+        Ok(move || {
+            rustix::io::write(&procs, b"0")?;
+            Ok(())
+        })
+    }
+
+    /// Whether a process of the cgroup was killed for running out of memory
+    pub fn oom_killed(&self) -> bool {
+        let Ok(events) = fs::read_to_string(self.path.join("memory.events")) else {
+            return false;
+        };
+        events
+            .lines()
+            .filter_map(|line| line.strip_prefix("oom_kill "))
+            .any(|count| count.trim() != "0")
+    }
+
+    /// Peak memory in bytes of the processes of the cgroup
+    pub fn memory_peak(&self) -> Option<u64> {
+        let peak = fs::read_to_string(self.path.join("memory.peak")).ok()?;
+        peak.trim().parse().ok()
+    }
+
+    /// CPU time in microseconds of the processes of the cgroup
+    pub fn cpu_time(&self) -> Option<u64> {
+        let stat = fs::read_to_string(self.path.join("cpu.stat")).ok()?;
+        stat.lines()
+            .find_map(|line| line.strip_prefix("usage_usec "))?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Kills all processes of the cgroup
+    pub fn kill(&self) {
+        if let Err(e) = fs::write(self.path.join("cgroup.kill"), "1") {
+            log::warn!("failed to kill cgroup {}: {e}", self.path.display());
+        }
+    }
+
+    /// Kills the processes left in the cgroup and removes it
+    pub async fn remove(self) {
+        self.kill();
+        for _ in 0..REMOVE_ATTEMPTS {
+            match fs::remove_dir(&self.path) {
+                // processes are still exiting
+                Err(e) if e.raw_os_error() == Some(Errno::BUSY.raw_os_error()) => {
+                    tokio::time::sleep(REMOVE_INTERVAL).await;
+                }
+                Err(e) => {
+                    log::warn!("failed to remove cgroup {}: {e}", self.path.display());
+                    return;
+                }
+                Ok(()) => return,
+            }
+        }
+        log::warn!("cgroup {} is still busy", self.path.display());
+    }
+}
@@ -0,0 +1,267 @@
+mod cgroup;
+
+use ace_bot_agent::protocol::{self, Exit, Frame, Spawn, read_frame, write_frame};
+use anyhow::Context;
+use clap::Parser;
+use rustix::process::{Gid, Pid, Signal, Uid, kill_process_group};
+use rustix::thread::{
+    UnshareFlags, set_thread_groups, set_thread_res_gid, set_thread_res_uid, unshare_unsafe,
+};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+use users::os::unix::UserExt;
+use users::{get_group_by_name, get_user_by_name};
+
+use cgroup::{Cgroup, Cgroups};
+
+const CHUNK_SIZE: usize = 8192;
+
+/// Runs commands of ace-bot inside the container
+#[derive(Clone, Debug, Parser)]
+#[command(author, version, about)]
+struct Options {
+    #[arg(long)]
+    socket: PathBuf,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum Error {
+    #[error("protocol error: {0}")]
+    Protocol(#[from] protocol::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing user: {0}")]
+    MissingUser(String),
+    #[error("missing group: {0}")]
+    MissingGroup(String),
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let options = Options::parse();
+    log::info!("Options = {options:#?}");
+    if options.socket.exists() {
+        std::fs::remove_file(&options.socket)?;
+    }
+    let listener = UnixListener::bind(&options.socket)?;
+    // only root may run commands through the agent
+    std::fs::set_permissions(&options.socket, PermissionsExt::from_mode(0o600))?;
+    // without the agent the bot falls back to units, which enforce the limits
+    let cgroups = Arc::new(Cgroups::init().context("resource limits can not be enforced")?);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let cgroups = cgroups.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &cgroups).await {
+                log::warn!("error: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, cgroups: &Cgroups) -> Result<(), Error> {
+    let (mut reader, mut writer) = stream.into_split();
+    let spawn = match read_frame(&mut reader).await? {
+        Some(Frame::Spawn(spawn)) => spawn,
+        Some(frame) => return Err(protocol::Error::UnexpectedFrame(frame.name()).into()),
+        None => return Ok(()),
+    };
+    log::debug!("spawn: {spawn:?}");
+    let cgroup = match cgroups.create(&spawn.limits) {
+        Ok(cgroup) => cgroup,
+        Err(e) => {
+            write_frame(&mut writer, &Frame::Error(e.to_string())).await?;
+            return Ok(());
+        }
+    };
+    let mut child = match spawn_child(&spawn, &cgroup) {
+        Ok(child) => child,
+        Err(e) => {
+            write_frame(&mut writer, &Frame::Error(e.to_string())).await?;
+            cgroup.remove().await;
+            return Ok(());
+        }
+    };
+    // frames are read in their own task, `read_frame` is not cancellation safe
+    let (frames, mut frame_receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Ok(Some(frame)) = read_frame(&mut reader).await {
+            if frames.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let group = child.id().and_then(|id| Pid::from_raw(id as i32));
+    let exit = supervise(
+        &mut child,
+        group,
+        &cgroup,
+        &spawn,
+        &mut frame_receiver,
+        &mut writer,
+    )
+    .await;
+    kill(group);
+    cgroup.remove().await;
+    match exit {
+        Ok(Some(exit)) => write_frame(&mut writer, &Frame::Exit(exit)).await?,
+        Ok(None) => log::debug!("client disconnected"),
+        Err(e) => write_frame(&mut writer, &Frame::Error(e.to_string())).await?,
+    }
+    Ok(())
+}
+
+fn spawn_child(spawn: &Spawn, cgroup: &Cgroup) -> Result<Child, Error> {
+    let user =
+        get_user_by_name(&spawn.user).ok_or_else(|| Error::MissingUser(spawn.user.clone()))?;
+    let group =
+        get_group_by_name(&spawn.group).ok_or_else(|| Error::MissingGroup(spawn.group.clone()))?;
+    let mut command = Command::new(&spawn.shell);
+    command
+        .arg("--login")
+        .current_dir(&spawn.working_directory)
+        .env_clear()
+        .env("HOME", user.home_dir())
+        .env("USER", user.name())
+        .env("LOGNAME", user.name())
+        .env("SHELL", &spawn.shell)
+        .envs(std::env::var_os("PATH").map(|path| ("PATH", path)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true);
+    let enter = cgroup.enter()?;
+    let offline = spawn.limits.offline;
+    let uid = Uid::from_raw(user.uid());
+    let gid = Gid::from_raw(group.gid());
+    // SAFETY: only system calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            enter()?;
+            // needs root, so root is dropped by hand afterwards
+            if offline {
+                unshare_unsafe(UnshareFlags::NEWNET)?;
+            }
+            // supplementary groups are dropped together with root
+            set_thread_groups(&[])?;
+            set_thread_res_gid(gid, gid, gid)?;
+            set_thread_res_uid(uid, uid, uid)?;
+            Ok(())
+        });
+    }
+    Ok(command.spawn()?)
+}
+
+/// Forwards stdin and output until the command exits and its output is closed
+///
+/// Once the command exits, the processes it left are killed with the cgroup,
+/// even those which left its process group. If output stays open anyway, it is
+/// no longer waited for after the timeout. Returns `None` if the client is
+/// gone.
+async fn supervise(
+    child: &mut Child,
+    group: Option<Pid>,
+    cgroup: &Cgroup,
+    spawn: &Spawn,
+    frames: &mut mpsc::Receiver<Frame>,
+    writer: &mut OwnedWriteHalf,
+) -> Result<Option<Exit>, Error> {
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    // stdin is written in its own task so that output is drained meanwhile
+    let (input, mut input_receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(data) = input_receiver.recv().await {
+            if stdin.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut input = Some(input);
+    let deadline = tokio::time::sleep(Duration::from_secs(spawn.timeout));
+    tokio::pin!(deadline);
+    let mut timed_out = false;
+    let mut status = None;
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+    let mut stdout_buffer = vec![0; CHUNK_SIZE];
+    let mut stderr_buffer = vec![0; CHUNK_SIZE];
+    while status.is_none() || (!timed_out && (stdout_open || stderr_open)) {
+        let frame = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(Frame::Stdin(data)) => {
+                    if let Some(input) = &input {
+                        let _ = input.send(data);
+                    }
+                    continue;
+                }
+                Some(Frame::StdinEnd) => {
+                    input = None;
+                    continue;
+                }
+                Some(Frame::Kill) => {
+                    kill(group);
+                    continue;
+                }
+                Some(frame) => return Err(protocol::Error::UnexpectedFrame(frame.name()).into()),
+                None => return Ok(None),
+            },
+            n = stdout.read(&mut stdout_buffer), if stdout_open => match n? {
+                0 => {
+                    stdout_open = false;
+                    continue;
+                }
+                n => Frame::Stdout(stdout_buffer[..n].to_vec()),
+            },
+            n = stderr.read(&mut stderr_buffer), if stderr_open => match n? {
+                0 => {
+                    stderr_open = false;
+                    continue;
+                }
+                n => Frame::Stderr(stderr_buffer[..n].to_vec()),
+            },
+            s = child.wait(), if status.is_none() => {
+                status = Some(s?);
+                // leftover background processes would keep the pipes open
+                kill(group);
+                cgroup.kill();
+                continue;
+            }
+            _ = &mut deadline, if !timed_out => {
+                timed_out = true;
+                kill(group);
+                cgroup.kill();
+                continue;
+            }
+        };
+        write_frame(writer, &frame).await?;
+    }
+    let oom_killed = cgroup.oom_killed();
+    // read before the cgroup is removed
+    let memory_peak = cgroup.memory_peak();
+    let cpu_time = cgroup.cpu_time();
+    Ok(status.map(|status| Exit {
+        memory_peak,
+        cpu_time,
+        ..Exit::new(status, timed_out, oom_killed)
+    }))
+}
+
+/// Kills the process group of the command
+fn kill(group: Option<Pid>) {
+    if let Some(group) = group {
+        let _ = kill_process_group(group, Signal::KILL);
+    }
+}
@@ -0,0 +1,190 @@
+//! Framed protocol between `AceBot` and the agent
+//!
+//! A connection runs exactly one command. The client sends [`Frame::Spawn`],
+//! then any number of [`Frame::Stdin`] followed by [`Frame::StdinEnd`], and
+//! may send [`Frame::Kill`] at any time. The agent answers with
+//! [`Frame::Stdout`] and [`Frame::Stderr`] chunks, and finally
+//! [`Frame::Exit`] or [`Frame::Error`]. Closing the connection kills the
+//! command.
+//!
+//! Each frame is a one byte tag, a big-endian `u32` payload length and the
+//! payload. Structured payloads are encoded as JSON.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound of payloads, larger frames are rejected
+pub const MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+
+const SPAWN: u8 = 0;
+const STDIN: u8 = 1;
+const STDIN_END: u8 = 2;
+const KILL: u8 = 3;
+const STDOUT: u8 = 4;
+const STDERR: u8 = 5;
+const EXIT: u8 = 6;
+const ERROR: u8 = 7;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Spawn(Spawn),
+    Stdin(Vec<u8>),
+    StdinEnd,
+    Kill,
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exit(Exit),
+    Error(String),
+}
+
+/// Runs `shell --login` as `user` and `group`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Spawn {
+    pub user: String,
+    pub group: String,
+    pub shell: String,
+    pub working_directory: PathBuf,
+    /// seconds before the command is killed
+    pub timeout: u64,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+/// Resource limits of the command, enforced through a cgroup of the agent
+/// except for network access
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// bytes of memory
+    pub memory_max: Option<u64>,
+    /// percentage of one CPU
+    pub cpu_quota: Option<u64>,
+    pub tasks_max: Option<u64>,
+    /// runs in a network namespace of its own
+    #[serde(default)]
+    pub offline: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exit {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    #[serde(default)]
+    pub oom_killed: bool,
+    /// bytes of the cgroup of the command, if known
+    #[serde(default)]
+    pub memory_peak: Option<u64>,
+    /// microseconds of the cgroup of the command, if known
+    #[serde(default)]
+    pub cpu_time: Option<u64>,
+}
+
+impl Exit {
+    pub fn new(status: ExitStatus, timed_out: bool, oom_killed: bool) -> Self {
+        Self {
+            code: status.code(),
+            signal: status.signal(),
+            timed_out,
+            oom_killed,
+            memory_peak: None,
+            cpu_time: None,
+        }
+    }
+
+    pub fn status(&self) -> ExitStatus {
+        match (self.code, self.signal) {
+            (Some(code), _) => ExitStatus::from_raw((code & 0xff) << 8),
+            (None, Some(signal)) => ExitStatus::from_raw(signal & 0x7f),
+            (None, None) => ExitStatus::from_raw(0xff << 8),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unknown frame: {0}")]
+    UnknownFrame(u8),
+    #[error("frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("unexpected frame: {0}")]
+    UnexpectedFrame(&'static str),
+}
+
+impl Frame {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Frame::Spawn(_) => "spawn",
+            Frame::Stdin(_) => "stdin",
+            Frame::StdinEnd => "stdin-end",
+            Frame::Kill => "kill",
+            Frame::Stdout(_) => "stdout",
+            Frame::Stderr(_) => "stderr",
+            Frame::Exit(_) => "exit",
+            Frame::Error(_) => "error",
+        }
+    }
+}
+
+/// Reads a frame, returns `None` if the connection is closed between frames
+///
+/// This is not cancellation safe, a partially read frame is lost.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, Error> {
+    let tag = match reader.read_u8().await {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_PAYLOAD {
+        return Err(Error::FrameTooLarge(len));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    let frame = match tag {
+        SPAWN => Frame::Spawn(serde_json::from_slice(&payload)?),
+        STDIN => Frame::Stdin(payload),
+        STDIN_END => Frame::StdinEnd,
+        KILL => Frame::Kill,
+        STDOUT => Frame::Stdout(payload),
+        STDERR => Frame::Stderr(payload),
+        EXIT => Frame::Exit(serde_json::from_slice(&payload)?),
+        ERROR => Frame::Error(String::from_utf8_lossy(&payload).into_owned()),
+        tag => return Err(Error::UnknownFrame(tag)),
+    };
+    Ok(Some(frame))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), Error> {
+    let (tag, payload): (_, Cow<[u8]>) = match frame {
+        Frame::Spawn(spawn) => (SPAWN, serde_json::to_vec(spawn)?.into()),
+        Frame::Stdin(data) => (STDIN, data.into()),
+        Frame::StdinEnd => (STDIN_END, Default::default()),
+        Frame::Kill => (KILL, Default::default()),
+        Frame::Stdout(data) => (STDOUT, data.into()),
+        Frame::Stderr(data) => (STDERR, data.into()),
+        Frame::Exit(exit) => (EXIT, serde_json::to_vec(exit)?.into()),
+        Frame::Error(message) => (ERROR, message.as_bytes().into()),
+    };
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Error::FrameTooLarge(payload.len()));
+    }
+    let mut buffer = Vec::with_capacity(5 + payload.len());
+    buffer.push(tag);
+    buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&payload);
+    writer.write_all(&buffer).await?;
+    writer.flush().await?;
+    Ok(())
+}
@@ -1,37 +1,61 @@
 // reference: https://github.com/matrix-org/matrix-rust-sdk/tree/main/examples/command_bot
 
 use ace_bot::{
-    AceBot, AceError, Mode,
+    AceBot, AceError, Mode, Request, Role,
+    execution::{Artifact, Execution, ExecutionResult},
+    flags::{self, Flags},
+    jobs::JobId,
+    modes::{ModeSpec, Output},
     pastebin::{self, curl_command},
+    scheduler::{Owner, Permit, Queued},
 };
 use clap::Parser;
-use futures::future::FutureExt;
+use futures::{StreamExt, future::FutureExt};
 use matrix_sdk::{
     Client, ClientBuildError, Room, RoomState,
+    attachment::AttachmentConfig,
     config::SyncSettings,
     event_handler::Ctx,
+    media::{MediaFormat, MediaRequestParameters},
     room::reply::{EnforceThread, Reply, ReplyError},
     ruma::{
-        OwnedRoomId, OwnedUserId,
-        events::room::{
-            member::StrippedRoomMemberEvent,
-            message::{
-                MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
-                RoomMessageEventContentWithoutRelation,
+        OwnedEventId, OwnedRoomId, OwnedUserId, UInt,
+        events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+            room::{
+                MediaSource,
+                member::StrippedRoomMemberEvent,
+                message::{
+                    MessageType, OriginalSyncRoomMessageEvent, Relation, ReplacementMetadata,
+                    RoomMessageEventContent, RoomMessageEventContentWithoutRelation,
+                    SyncRoomMessageEvent,
+                },
             },
         },
     },
 };
 use regex::{Regex, RegexBuilder};
 use std::{
+    collections::HashMap,
     fmt::Display,
     ops::Deref,
-    process::Output,
-    sync::{Arc, LazyLock},
+    sync::{Arc, LazyLock, Mutex},
     time::Duration,
 };
 use tokio::time::sleep;
 
+/// A file sent in a room, saved to the inbox
+#[derive(Clone, Debug)]
+struct Upload {
+    source: MediaSource,
+    name: String,
+    size: Option<UInt>,
+    caption: Option<String>,
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+const PROGRESS_LIMIT: usize = 1000;
+
 #[derive(Debug, Clone)]
 struct ArcContext(Arc<Context>);
 impl Deref for ArcContext {
@@ -47,6 +71,10 @@ struct Context {
     ace: AceBot,
     options: MatrixOptions,
     client: Client,
+    /// running jobs by their command and progress events, for `!cancel`
+    jobs: Mutex<HashMap<OwnedEventId, JobId>>,
+    /// command patterns of the modes in the registry
+    modes: Vec<(Regex, &'static ModeSpec)>,
 }
 
 impl Context {
@@ -57,14 +85,32 @@ impl Context {
             .build()
             .await
             .map_err(Box::new)?;
+        let ace = AceBot::new(options.ace)?;
+        let modes = ace
+            .modes()
+            .iter()
+            .filter_map(|mode| match mode {
+                Mode::Custom(spec) => Some((mode_command_pattern(spec), *spec)),
+                Mode::NonRoot | Mode::Root => None,
+            })
+            .collect();
         Ok(Self {
-            ace: AceBot::new(options.ace)?,
+            ace,
             options: options.matrix,
             client,
+            jobs: Default::default(),
+            modes,
         })
     }
 }
 
+fn mode_command_pattern(spec: &ModeSpec) -> Regex {
+    RegexBuilder::new(&spec.command_pattern("!"))
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+}
+
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about)]
 struct FullOptions {
@@ -119,13 +165,54 @@ static RESET_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
         .build()
         .unwrap()
 });
-static NIX_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
-    RegexBuilder::new("^(!nix@[a-zA-Z_]+|!nix)[[:space:]]*(.*)$")
+static CANCEL_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!cancel@[a-zA-Z_]+|!cancel)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static KILL_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!kill@[a-zA-Z_]+|!kill)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static JOBS_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!jobs@[a-zA-Z_]+|!jobs)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static GET_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!get@[a-zA-Z_]+|!get)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static PWD_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!pwd@[a-zA-Z_]+|!pwd)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static SESSION_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!session@[a-zA-Z_]+|!session)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static END_SESSION_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!endsession@[a-zA-Z_]+|!endsession)[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static BG_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^(!bg@[a-zA-Z_]+|!bg)[[:space:]]*(.*)$")
         .dot_matches_new_line(true)
         .build()
         .unwrap()
 });
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
@@ -208,24 +295,99 @@ impl ArcContext {
         if room.state() != RoomState::Joined {
             return Ok(());
         }
-        let MessageType::Text(text_content) = &event.content.msgtype else {
-            return Ok(());
+        let upload = match &event.content.msgtype {
+            MessageType::Text(text_content) => {
+                self.handle_text(&event, room, &text_content.body);
+                return Ok(());
+            }
+            MessageType::File(file_content) => Upload {
+                source: file_content.source.clone(),
+                name: file_content.filename().to_string(),
+                size: file_content.info.as_ref().and_then(|info| info.size),
+                caption: file_content.caption().map(str::to_string),
+            },
+            MessageType::Image(image_content) => Upload {
+                source: image_content.source.clone(),
+                name: image_content.filename().to_string(),
+                size: image_content.info.as_ref().and_then(|info| info.size),
+                caption: image_content.caption().map(str::to_string),
+            },
+            _ => return Ok(()),
         };
+        // files are taken from direct rooms, and elsewhere only with a command as caption
+        let is_command = upload.caption.as_ref().is_some_and(|c| c.starts_with('!'));
+        if is_command || room.is_direct().await.unwrap_or(false) {
+            tokio::spawn(self.handle_upload(event, room, upload).map(log_error));
+        }
+        Ok(())
+    }
 
-        let raw_text = &text_content.body;
+    /// Dispatches a text message, or the caption of an upload, to its command
+    fn handle_text(&self, event: &OriginalSyncRoomMessageEvent, room: Room, raw_text: &str) {
         let user = &event.sender;
         log::debug!("{user} raw: {raw_text}");
         if RESET_COMMAND_PATTERN.is_match(raw_text) {
             tokio::spawn(
-                self.handle_reset(event.clone(), room, user.clone())
+                self.clone()
+                    .handle_reset(event.clone(), room, user.clone())
                     .map(log_error),
             );
-            return Ok(());
+            return;
+        }
+        if SESSION_COMMAND_PATTERN.is_match(raw_text) {
+            tokio::spawn(
+                self.clone()
+                    .handle_session(event.clone(), room, user.clone())
+                    .map(log_error),
+            );
+            return;
+        }
+        if END_SESSION_COMMAND_PATTERN.is_match(raw_text) {
+            tokio::spawn(
+                self.clone()
+                    .handle_end_session(event.clone(), room)
+                    .map(log_error),
+            );
+            return;
+        }
+        if JOBS_COMMAND_PATTERN.is_match(raw_text) {
+            tokio::spawn(self.clone().handle_jobs(event.clone(), room).map(log_error));
+            return;
+        }
+        if PWD_COMMAND_PATTERN.is_match(raw_text) {
+            tokio::spawn(self.clone().handle_pwd(event.clone(), room).map(log_error));
+            return;
+        }
+        if let Some(c) = GET_COMMAND_PATTERN.captures(raw_text) {
+            let argument = c[2].trim().to_string();
+            tokio::spawn(
+                self.clone()
+                    .handle_get(event.clone(), room, argument)
+                    .map(log_error),
+            );
+            return;
+        }
+        if let Some(c) = CANCEL_COMMAND_PATTERN
+            .captures(raw_text)
+            .or_else(|| KILL_COMMAND_PATTERN.captures(raw_text))
+        {
+            let argument = c[2].trim().to_string();
+            tokio::spawn(
+                self.clone()
+                    .handle_cancel(event.clone(), room, user.clone(), argument)
+                    .map(log_error),
+            );
+            return;
         }
         let mode;
         let command;
-        if let Some(c) = NIX_COMMAND_PATTERN.captures(raw_text) {
-            mode = Mode::Nix;
+        let mut background = false;
+        if let Some((c, spec)) = self
+            .modes
+            .iter()
+            .find_map(|(pattern, spec)| Some((pattern.captures(raw_text)?, *spec)))
+        {
+            mode = Mode::Custom(spec);
             command = c[2].to_string();
         } else if let Some(c) = ROOT_COMMAND_PATTERN.captures(raw_text) {
             mode = Mode::Root;
@@ -233,16 +395,19 @@ impl ArcContext {
         } else if let Some(c) = USER_COMMAND_PATTERN.captures(raw_text) {
             mode = Mode::NonRoot;
             command = c[2].to_string();
+        } else if let Some(c) = BG_COMMAND_PATTERN.captures(raw_text) {
+            mode = Mode::NonRoot;
+            command = c[2].to_string();
+            background = true;
         } else {
             log::debug!("ignored event: {event:?}");
-            return Ok(());
+            return;
         }
         tokio::spawn(
-            self.handle_command(event.clone(), room, user.clone(), mode, command)
+            self.clone()
+                .handle_command(event.clone(), room, user.clone(), mode, command, background)
                 .map(log_error),
         );
-
-        Ok(())
     }
 
     async fn handle_command(
@@ -252,8 +417,87 @@ impl ArcContext {
         user: OwnedUserId,
         mode: Mode,
         command: String,
+        background: bool,
     ) -> Result<(), Error> {
-        match self.ace.run(mode, &command).await {
+        let (flags, command) = match Flags::parse(&command) {
+            Ok((flags, command)) => (flags, command.to_string()),
+            Err(e) => return report_ace_error(&e, &event, &room).await,
+        };
+        let stdin = match mode {
+            Mode::NonRoot | Mode::Root => match self.replied_stdin(&event, &room).await {
+                Err(Error::Ace(e)) => return report_ace_error(&e, &event, &room).await,
+                result => result?,
+            },
+            _ => None,
+        };
+        let role = match self.is_manager_room(&room) {
+            true => Role::Manager,
+            false => Role::Member,
+        };
+        // captions run next to the file they came with
+        let in_inbox = matches!(
+            event.content.msgtype,
+            MessageType::File(_) | MessageType::Image(_)
+        );
+        let request = Request {
+            owner: Owner {
+                chat: room.room_id().to_string(),
+                user: user.to_string(),
+            },
+            mode,
+            text: command.clone(),
+            background,
+            timeout: flags.timeout,
+            offline: flags.offline,
+            engine: flags.engine,
+            role,
+            stdin,
+            in_inbox,
+        };
+        if let Err(e) = self.ace.validate(&request) {
+            return report_ace_error(&e, &event, &room).await;
+        }
+        let queued = self.ace.enqueue(&request);
+        let position = *queued.position().borrow();
+        let progress = reply(&event, &room, &queue_text(position)).await?;
+        let permit = wait_in_queue(queued, position, &room, &progress).await;
+        let keys = [event.event_id.clone(), progress.clone()];
+        let in_session = matches!(mode, Mode::NonRoot)
+            && !background
+            // the network of a running session can not be cut
+            && !request.offline
+            && request.stdin.is_none()
+            && !request.in_inbox
+            && self.ace.has_session(&request.owner.chat);
+        let execution = if in_session {
+            self.ace.run_in_session(&request)
+        } else {
+            self.ace.spawn(&request).await
+        };
+        let result = match execution {
+            Ok(execution) => {
+                let id = execution.id();
+                self.jobs
+                    .lock()
+                    .unwrap()
+                    .extend(keys.iter().map(|key| (key.clone(), id)));
+                if background {
+                    wait_background(execution, &room, &progress).await
+                } else {
+                    follow_execution(execution, &room, &progress).await
+                }
+            }
+            Err(e) => Err(e),
+        };
+        drop(permit);
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|key, _| !keys.contains(key));
+        if let Err(e) = room.redact(&progress, None, None).await {
+            log::warn!("failed to redact progress message: {e}");
+        }
+        match result {
             Err(e) => report_ace_error(&e, &event, &room).await,
             Ok(output) => {
                 let output_message =
@@ -263,6 +507,218 @@ impl ArcContext {
         }
     }
 
+    /// Saves the file to the inbox, then runs its caption if it is a command
+    async fn handle_upload(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+        upload: Upload,
+    ) -> Result<(), Error> {
+        let size = upload.size.map_or(0, |size| u64::from(size) as usize);
+        if let Err(e) = self.ace.validate_upload(&upload.name, size) {
+            return report_ace_error(&e, &event, &room).await;
+        }
+        let data = self.download(upload.source).await?;
+        match self
+            .ace
+            .upload(room.room_id().as_str(), &upload.name, &data)
+            .await
+        {
+            Err(e) => return report_ace_error(&e, &event, &room).await,
+            Ok(path) => {
+                let text = format!("saved to {}", path.display());
+                reply(&event, &room, &text).await?;
+            }
+        }
+        if let Some(caption) = upload.caption.filter(|c| c.starts_with('!')) {
+            self.handle_text(&event, room, &caption);
+        }
+        Ok(())
+    }
+
+    async fn download(&self, source: MediaSource) -> Result<Vec<u8>, Error> {
+        let request = MediaRequestParameters {
+            source,
+            format: MediaFormat::File,
+        };
+        Ok(self
+            .client
+            .media()
+            .get_media_content(&request, false)
+            .await?)
+    }
+
+    /// Text or file of the event replied to, read by shell commands from stdin
+    async fn replied_stdin(
+        &self,
+        event: &OriginalSyncRoomMessageEvent,
+        room: &Room,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let Some(Relation::Reply { in_reply_to, .. }) = &event.content.relates_to else {
+            return Ok(None);
+        };
+        let replied = room.event(&in_reply_to.event_id, None).await?;
+        let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncRoomMessageEvent::Original(replied),
+        ))) = replied.raw().deserialize()
+        else {
+            return Ok(None);
+        };
+        match replied.content.msgtype {
+            MessageType::Text(text_content) => Ok(Some(text_content.body.into_bytes())),
+            MessageType::File(file_content) => {
+                // checked before the download, as for uploads
+                let size = file_content.info.as_ref().and_then(|info| info.size);
+                self.ace
+                    .validate_size(size.map_or(0, |size| u64::from(size) as usize))?;
+                self.download(file_content.source).await.map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Cancels the job given by id, or the job of the replied event
+    async fn handle_cancel(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+        user: OwnedUserId,
+        argument: String,
+    ) -> Result<(), Error> {
+        let id = if argument.is_empty() {
+            match &event.content.relates_to {
+                Some(Relation::Reply { in_reply_to, .. }) => {
+                    let jobs = self.jobs.lock().unwrap();
+                    jobs.get(&in_reply_to.event_id).copied()
+                }
+                _ => None,
+            }
+        } else {
+            argument.parse().ok()
+        };
+        let Some(id) = id else {
+            reply(
+                &event,
+                &room,
+                "usage: reply to a running job, or give its id",
+            )
+            .await?;
+            return Ok(());
+        };
+        let requester = Owner {
+            chat: room.room_id().to_string(),
+            user: user.to_string(),
+        };
+        let privileged = self.is_manager_room(&room);
+        match self.ace.cancel(id, &requester, privileged).await {
+            Err(e) => report_ace_error(&e, &event, &room).await,
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Starts a session, later `!user` commands of the room are fed to it
+    async fn handle_session(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+        user: OwnedUserId,
+    ) -> Result<(), Error> {
+        let owner = Owner {
+            chat: room.room_id().to_string(),
+            user: user.to_string(),
+        };
+        match self.ace.start_session(&owner).await {
+            Err(e) => report_ace_error(&e, &event, &room).await,
+            Ok(()) => {
+                let text = "session started, !user commands run in it until !endsession";
+                reply(&event, &room, text).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_end_session(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+    ) -> Result<(), Error> {
+        match self.ace.end_session(room.room_id().as_str()) {
+            Err(e) => report_ace_error(&e, &event, &room).await,
+            Ok(()) => {
+                reply(&event, &room, "session ended").await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Shows the working directory of the room
+    async fn handle_pwd(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+    ) -> Result<(), Error> {
+        match self.ace.workdir(room.room_id().as_str()) {
+            Err(e) => report_ace_error(&e, &event, &room).await,
+            Ok(workdir) => {
+                reply(&event, &room, &workdir.display().to_string()).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends a file of the home as an attachment
+    async fn handle_get(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+        argument: String,
+    ) -> Result<(), Error> {
+        let (zip, path) = flags::parse_get(&argument);
+        if path.is_empty() {
+            reply(&event, &room, "usage: !get [--zip] PATH").await?;
+            return Ok(());
+        }
+        match self.ace.get(room.room_id().as_str(), path, zip).await {
+            Err(e) => report_ace_error(&e, &event, &room).await,
+            Ok(file) => {
+                let content_type = mime_guess::from_path(&file.name).first_or_octet_stream();
+                room.send_attachment(
+                    file.name.as_str(),
+                    &content_type,
+                    file.data,
+                    AttachmentConfig::new(),
+                )
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Lists running jobs of the room, or of all rooms in the manager room
+    async fn handle_jobs(
+        self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+    ) -> Result<(), Error> {
+        let chat = room.room_id().to_string();
+        let filter = (!self.is_manager_room(&room)).then_some(chat.as_str());
+        let jobs = self.ace.jobs(filter);
+        let text = if jobs.is_empty() {
+            "no running jobs".to_string()
+        } else {
+            jobs.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        reply(&event, &room, &text).await?;
+        Ok(())
+    }
+
+    fn is_manager_room(&self, room: &Room) -> bool {
+        self.options.manager_room.as_deref() == Some(room.room_id())
+    }
+
     async fn handle_reset(
         self,
         event: OriginalSyncRoomMessageEvent,
@@ -271,8 +727,8 @@ impl ArcContext {
     ) -> Result<(), Error> {
         match self.ace.reset().await {
             Err(e) => report_ace_error(&e, &event, &room).await,
-            Ok(output) => {
-                let output_message = OutputMessage::format(&user, None, "/reset", output).await;
+            Ok(()) => {
+                let output_message = OutputMessage::meta(&user, "/reset", "environment reset");
                 self.handle_output(&room, output_message).await
             }
         }
@@ -305,6 +761,7 @@ impl ArcContext {
 #[derive(Debug)]
 pub struct OutputMessage {
     message: String,
+    artifacts: Vec<Artifact>,
 }
 
 impl OutputMessage {
@@ -312,7 +769,7 @@ impl OutputMessage {
         user: &OwnedUserId,
         mode: Option<Mode>,
         command: &str,
-        output: Output,
+        output: ExecutionResult,
     ) -> OutputMessage {
         let user = user_indicator(user);
         const PART_LIMIT: usize = 1000;
@@ -338,11 +795,54 @@ impl OutputMessage {
             message.push('\n');
         }
 
-        message.push_str(&format!("{}", output.status));
+        message.push_str(&output.summary());
+        if let Some(diagnostics) = &output.diagnostics
+            && !diagnostics.output.trim_ascii().is_empty()
+        {
+            // apart from stderr, which belongs to the program
+            message.push_str("\n(compiler)");
+            if diagnostics.omitted != 0 {
+                message.push_str(&format!(
+                    "\noutput truncated, {} bytes omitted",
+                    diagnostics.omitted
+                ));
+            }
+            match String::from_utf8(diagnostics.output.clone()) {
+                Ok(s) if s.len() < PART_LIMIT => message.push_str(&format!("\n{s}")),
+                _ => {
+                    if let Ok(cmd) =
+                        curl_command(&client, "compiler", diagnostics.output.clone()).await
+                    {
+                        message.push_str(&format!("\n{}", &cmd))
+                    }
+                }
+            }
+        }
+        // images and documents of modes are attached rather than inlined
+        let mut attachments = Vec::new();
+        let attach = match mode.and_then(|m| m.output()) {
+            Some(Output::Image | Output::Audio | Output::Document) => {
+                mode.map(|m| m.output_name().to_string())
+            }
+            Some(Output::Text) | None => None,
+        };
         if !output.stdout.is_empty() {
             message.push_str(&format!("\n{}", "(stdout)"));
+            if output.stdout_omitted != 0 {
+                message.push_str(&format!(
+                    "\noutput truncated, {} bytes omitted",
+                    output.stdout_omitted
+                ));
+            }
             let mut inlined = false;
-            if let Ok(s) = String::from_utf8(output.stdout.clone())
+            if let Some(name) = attach {
+                inlined = true;
+                message.push_str(&format!("\n{name} attached"));
+                attachments.push(Artifact {
+                    name,
+                    data: output.stdout.clone(),
+                });
+            } else if let Ok(s) = String::from_utf8(output.stdout.clone())
                 && s.len() < PART_LIMIT
             {
                 inlined = true;
@@ -363,6 +863,12 @@ impl OutputMessage {
 
         if !output.stderr.is_empty() {
             message.push_str(&format!("\n{}", "(stderr)"));
+            if output.stderr_omitted != 0 {
+                message.push_str(&format!(
+                    "\noutput truncated, {} bytes omitted",
+                    output.stderr_omitted
+                ));
+            }
             let mut inlined = false;
             if let Ok(s) = String::from_utf8(output.stderr.clone())
                 && s.len() < PART_LIMIT
@@ -381,16 +887,139 @@ impl OutputMessage {
             }
         }
 
-        OutputMessage { message }
+        let artifacts = output.artifacts;
+        if !artifacts.files.is_empty() || artifacts.omitted != 0 {
+            message.push_str("\n(artifacts)");
+            let names: Vec<_> = artifacts.files.iter().map(|a| a.name.as_str()).collect();
+            if !names.is_empty() {
+                message.push_str(&format!("\n{}", names.join(", ")));
+            }
+            if artifacts.omitted != 0 {
+                message.push_str(&format!(
+                    "\n{} files omitted, artifacts are limited in number and size",
+                    artifacts.omitted
+                ));
+            }
+        }
+
+        attachments.extend(artifacts.files);
+        OutputMessage {
+            message,
+            artifacts: attachments,
+        }
     }
 
+    /// A meta command finished without output of its own
+    fn meta(user: &OwnedUserId, command: &str, text: &str) -> OutputMessage {
+        OutputMessage {
+            message: format!("{} (meta):\n{command}\n{text}", user_indicator(user)),
+            artifacts: Vec::new(),
+        }
+    }
+
+    /// Sends the message, followed by the artifacts as attachments
     async fn send(&self, room: &Room) -> Result<(), Error> {
         let message = RoomMessageEventContent::text_plain(&self.message);
         room.send(message).await?;
+        for artifact in &self.artifacts {
+            let content_type = mime_guess::from_path(&artifact.name).first_or_octet_stream();
+            room.send_attachment(
+                artifact.name.as_str(),
+                &content_type,
+                artifact.data.clone(),
+                AttachmentConfig::new(),
+            )
+            .await?;
+        }
         Ok(())
     }
 }
 
+fn running_text(id: JobId) -> String {
+    format!("job {id} running...")
+}
+
+fn queue_text(position: usize) -> String {
+    match position {
+        0 => "running...".to_string(),
+        p => format!("queued, position {p}"),
+    }
+}
+
+/// Waits for the permit, keeping the queue position shown in `progress` up to date
+async fn wait_in_queue(
+    queued: Queued,
+    mut shown: usize,
+    room: &Room,
+    progress: &OwnedEventId,
+) -> Permit {
+    let mut position = queued.position();
+    let wait = queued.wait();
+    tokio::pin!(wait);
+    loop {
+        let current = tokio::select! {
+            permit = &mut wait => {
+                if shown != 0 {
+                    let _ = edit(room, progress, &queue_text(0)).await;
+                }
+                return permit;
+            }
+            Ok(()) = position.changed() => *position.borrow_and_update(),
+        };
+        if current == shown || current == 0 {
+            continue;
+        }
+        shown = current;
+        if let Err(e) = edit(room, progress, &queue_text(current)).await {
+            log::debug!("failed to update queue position: {e}");
+        }
+    }
+}
+
+async fn follow_execution(
+    mut execution: Execution,
+    room: &Room,
+    progress: &OwnedEventId,
+) -> Result<ExecutionResult, AceError> {
+    let id = execution.id();
+    if let Err(e) = edit(room, progress, &running_text(id)).await {
+        log::debug!("failed to update progress message: {e}");
+    }
+    let mut collector = execution.collector(PROGRESS_LIMIT);
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            event = execution.next() => match event {
+                Some(event) => collector.push(event?),
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if !collector.take_changed() {
+                    continue;
+                }
+                let text = format!("{}\n{}", running_text(id), collector.live());
+                if let Err(e) = edit(room, progress, &text).await {
+                    log::debug!("failed to update progress message: {e}");
+                }
+            }
+        }
+    }
+    collector.into_result()
+}
+
+/// Waits for a background job, the result is reported without progress
+async fn wait_background(
+    execution: Execution,
+    room: &Room,
+    progress: &OwnedEventId,
+) -> Result<ExecutionResult, AceError> {
+    let text = format!("job {} running in background", execution.id());
+    if let Err(e) = edit(room, progress, &text).await {
+        log::debug!("failed to update progress message: {e}");
+    }
+    execution.result().await
+}
+
 fn log_error<E: Display>(r: Result<(), E>) {
     if let Err(e) = r {
         log::warn!("error: {e}")
@@ -407,20 +1036,28 @@ pub async fn report_ace_error(
     room: &Room,
 ) -> Result<(), Error> {
     log::warn!("report error to room {}: {:?}", room.room_id(), err);
-    reply(event, room, &format!("{err}")).await
+    reply(event, room, &format!("{err}")).await?;
+    Ok(())
+}
+
+pub async fn edit(room: &Room, event_id: &OwnedEventId, text: &str) -> Result<(), Error> {
+    let content = RoomMessageEventContentWithoutRelation::text_plain(text)
+        .make_replacement(ReplacementMetadata::new(event_id.clone(), None));
+    room.send(content).await?;
+    Ok(())
 }
 
 pub async fn reply(
     event: &OriginalSyncRoomMessageEvent,
     room: &Room,
     text: &str,
-) -> Result<(), Error> {
+) -> Result<OwnedEventId, Error> {
     let message = RoomMessageEventContentWithoutRelation::text_plain(text);
     let reply = Reply {
         event_id: event.event_id.clone(),
         enforce_thread: EnforceThread::MaybeThreaded,
     };
     let reply_event = room.make_reply_event(message, reply).await?;
-    room.send(reply_event).await?;
-    Ok(())
+    let result = room.send(reply_event).await?;
+    Ok(result.response.event_id)
 }
@@ -1,9 +1,17 @@
 use ace_bot::AceBot;
 use ace_bot::AceError;
 use ace_bot::Mode;
+use ace_bot::Request;
+use ace_bot::Role;
+use ace_bot::execution::{Execution, ExecutionResult};
+use ace_bot::flags::{self, Flags};
+use ace_bot::jobs::JobId;
+use ace_bot::modes::{ModeSpec, Output};
 use ace_bot::pastebin;
 use ace_bot::pastebin::curl_command;
+use ace_bot::scheduler::{Owner, Permit, Queued};
 use clap::Parser;
+use futures::StreamExt;
 use futures::future::FutureExt;
 use magick_rust::MagickError;
 use magick_rust::MagickWand;
@@ -13,18 +21,20 @@ use magick_rust::magick_wand_terminus;
 use regex::Regex;
 use regex::RegexBuilder;
 use std::cell::LazyCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
 use std::ops::Deref;
-use std::process::Output;
-use std::sync::{Arc, LazyLock};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
 use teloxide::RequestError;
+use teloxide::net::Download;
 use teloxide::types::InputFile;
 use teloxide::types::InputMedia;
 use teloxide::types::InputMediaAnimation;
+use teloxide::types::InputMediaAudio;
 use teloxide::types::InputMediaDocument;
 use teloxide::types::InputMediaPhoto;
-use teloxide::types::{ParseMode, User};
+use teloxide::types::{FileMeta, MessageId, ParseMode, User};
 use teloxide::utils::markdown;
 use teloxide::{
     prelude::*,
@@ -42,6 +52,21 @@ thread_local! {
     });
 }
 
+/// Images Telegram takes as photos, others are sent as documents
+const PHOTO_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+/// Audio Telegram plays in audio messages
+const AUDIO_TYPES: &[&str] = &["audio/mpeg", "audio/mp4", "audio/x-m4a"];
+
+/// Mime type of `data` by its magic bytes, without parameters, e.g. `image/png`
+fn mime_type(data: &[u8]) -> String {
+    let mime = COOKIE.with(|cookie| cookie.buffer(data).unwrap_or_default());
+    match mime.split_once(';') {
+        Some((mime, _)) => mime.trim().to_string(),
+        None => mime,
+    }
+}
+
 #[derive(Debug, Clone)]
 struct ArcContext(Arc<Context>);
 impl Deref for ArcContext {
@@ -56,17 +81,39 @@ impl Deref for ArcContext {
 struct Context {
     ace: AceBot,
     options: TgOptions,
+    /// running jobs by their command and progress messages, for `/cancel`
+    jobs: Mutex<HashMap<(ChatId, MessageId), JobId>>,
+    /// command patterns of the modes in the registry
+    modes: Vec<(Regex, &'static ModeSpec)>,
 }
 
 impl Context {
     fn new(options: FullOptions) -> Result<Self, Error> {
+        let ace = AceBot::new(options.ace)?;
+        let modes = ace
+            .modes()
+            .iter()
+            .filter_map(|mode| match mode {
+                Mode::Custom(spec) => Some((mode_command_pattern(spec), *spec)),
+                Mode::NonRoot | Mode::Root => None,
+            })
+            .collect();
         Ok(Self {
-            ace: AceBot::new(options.ace)?,
+            ace,
             options: options.tg,
+            jobs: Default::default(),
+            modes,
         })
     }
 }
 
+fn mode_command_pattern(spec: &ModeSpec) -> Regex {
+    RegexBuilder::new(&spec.command_pattern("/"))
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+}
+
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about)]
 struct FullOptions {
@@ -95,6 +142,11 @@ pub enum Error {
     Magick(#[from] MagickError),
 }
 
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+const PROGRESS_LIMIT: usize = 1000;
+/// Telegram accepts at most 10 files in a media group
+const MEDIA_GROUP_LIMIT: usize = 10;
+
 static START_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
     RegexBuilder::new("^/start(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
         .dot_matches_new_line(true)
@@ -119,25 +171,54 @@ static RESET_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
         .build()
         .unwrap()
 });
-static NIX_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
-    RegexBuilder::new("^/nix(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+static CANCEL_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/cancel(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
         .dot_matches_new_line(true)
         .build()
         .unwrap()
 });
-static XELATEX_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
-    RegexBuilder::new("^/xelatex(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+static KILL_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/kill(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
         .dot_matches_new_line(true)
         .build()
         .unwrap()
 });
-static TYPST_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
-    RegexBuilder::new("^/typst(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+static JOBS_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/jobs(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static GET_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/get(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static PWD_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/pwd(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static SESSION_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/session(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static END_SESSION_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/endsession(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+});
+static BG_COMMAND_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new("^/bg(@[a-zA-Z_]+)?[[:space:]]*(.*)$")
         .dot_matches_new_line(true)
         .build()
         .unwrap()
 });
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
@@ -164,56 +245,45 @@ async fn main() -> anyhow::Result<()> {
 
 async fn handle_message(ctx: ArcContext, message: Message, bot: Bot) -> Result<(), ()> {
     match &message.kind {
-        MessageKind::Common(common_msg) => match &common_msg.media_kind {
-            MediaKind::Text(text_media) => match &common_msg.from {
-                Some(user) => {
-                    let raw_text = &text_media.text;
-                    log::debug!("{user:?} raw: {raw_text}");
-                    if START_COMMAND_PATTERN.is_match(raw_text) {
-                        tokio::spawn(
-                            ctx.handle_start(message.clone(), bot.clone())
-                                .map(log_error),
-                        );
-                        return Ok(());
-                    }
-                    if RESET_COMMAND_PATTERN.is_match(raw_text) {
-                        tokio::spawn(
-                            ctx.handle_reset(message.clone(), bot.clone(), user.clone())
-                                .map(log_error),
-                        );
-                        return Ok(());
-                    }
-                    let mode;
-                    let command;
-                    if let Some(c) = NIX_COMMAND_PATTERN.captures(raw_text) {
-                        mode = Mode::Nix;
-                        command = c[2].to_string();
-                    } else if let Some(c) = XELATEX_COMMAND_PATTERN.captures(raw_text) {
-                        mode = Mode::Xelatex;
-                        command = c[2].to_string();
-                    } else if let Some(c) = TYPST_COMMAND_PATTERN.captures(raw_text) {
-                        mode = Mode::Typst;
-                        command = c[2].to_string();
-                    } else if let Some(c) = ROOT_COMMAND_PATTERN.captures(raw_text) {
-                        mode = Mode::Root;
-                        command = preprocessing(&c[2]);
-                    } else if let Some(c) = USER_COMMAND_PATTERN.captures(raw_text) {
-                        mode = Mode::NonRoot;
-                        command = preprocessing(&c[2]);
-                    } else if message.chat.id.is_user() {
-                        mode = Mode::NonRoot;
-                        command = preprocessing(raw_text);
-                    } else {
-                        log::debug!("ignored update: {message:?}");
-                        return Ok(());
-                    }
-                    tokio::spawn(
-                        ctx.handle_command(message.clone(), bot, user.clone(), mode, command)
-                            .map(log_error),
-                    );
-                }
-                _ => log::debug!("ignored update: {message:?}"),
-            },
+        MessageKind::Common(common_msg) => match (&common_msg.media_kind, &common_msg.from) {
+            (MediaKind::Text(text_media), Some(user)) => {
+                handle_text(&ctx, &message, &bot, user, &text_media.text)
+            }
+            (MediaKind::Document(document_media), Some(user))
+                if is_addressed(&message, document_media.caption.as_deref()) =>
+            {
+                let document = &document_media.document;
+                let upload = Upload {
+                    file: document.file.clone(),
+                    name: document
+                        .file_name
+                        .clone()
+                        .unwrap_or_else(|| document.file.unique_id.clone()),
+                    caption: document_media.caption.clone(),
+                };
+                tokio::spawn(
+                    ctx.handle_upload(message.clone(), bot, user.clone(), upload)
+                        .map(log_error),
+                );
+            }
+            (MediaKind::Photo(photo_media), Some(user))
+                if is_addressed(&message, photo_media.caption.as_deref()) =>
+            {
+                // sizes are ordered from the smallest
+                let Some(photo) = photo_media.photo.last() else {
+                    log::debug!("ignored update: {message:?}");
+                    return Ok(());
+                };
+                let upload = Upload {
+                    file: photo.file.clone(),
+                    name: format!("{}.jpg", photo.file.unique_id),
+                    caption: photo_media.caption.clone(),
+                };
+                tokio::spawn(
+                    ctx.handle_upload(message.clone(), bot, user.clone(), upload)
+                        .map(log_error),
+                );
+            }
             _ => log::debug!("ignored update: {message:?}"),
         },
         _ => log::debug!("ignored update: {message:?}"),
@@ -221,6 +291,135 @@ async fn handle_message(ctx: ArcContext, message: Message, bot: Bot) -> Result<(
     Ok(())
 }
 
+/// Files are taken from private chats, and in groups only with a command as caption
+fn is_addressed(message: &Message, caption: Option<&str>) -> bool {
+    message.chat.id.is_user() || caption.is_some_and(|c| c.starts_with('/'))
+}
+
+/// A file sent in chat, saved to the inbox
+#[derive(Clone, Debug)]
+struct Upload {
+    file: FileMeta,
+    name: String,
+    caption: Option<String>,
+}
+
+/// Dispatches a text message, or the caption of an upload, to its command
+fn handle_text(ctx: &ArcContext, message: &Message, bot: &Bot, user: &User, raw_text: &str) {
+    log::debug!("{user:?} raw: {raw_text}");
+    if START_COMMAND_PATTERN.is_match(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_start(message.clone(), bot.clone())
+                .map(log_error),
+        );
+        return;
+    }
+    if RESET_COMMAND_PATTERN.is_match(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_reset(message.clone(), bot.clone(), user.clone())
+                .map(log_error),
+        );
+        return;
+    }
+    if SESSION_COMMAND_PATTERN.is_match(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_session(message.clone(), bot.clone(), user.clone())
+                .map(log_error),
+        );
+        return;
+    }
+    if END_SESSION_COMMAND_PATTERN.is_match(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_end_session(message.clone(), bot.clone())
+                .map(log_error),
+        );
+        return;
+    }
+    if JOBS_COMMAND_PATTERN.is_match(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_jobs(message.clone(), bot.clone())
+                .map(log_error),
+        );
+        return;
+    }
+    if PWD_COMMAND_PATTERN.is_match(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_pwd(message.clone(), bot.clone())
+                .map(log_error),
+        );
+        return;
+    }
+    if let Some(c) = GET_COMMAND_PATTERN.captures(raw_text) {
+        tokio::spawn(
+            ctx.clone()
+                .handle_get(message.clone(), bot.clone(), c[2].trim().to_string())
+                .map(log_error),
+        );
+        return;
+    }
+    if let Some(c) = CANCEL_COMMAND_PATTERN
+        .captures(raw_text)
+        .or_else(|| KILL_COMMAND_PATTERN.captures(raw_text))
+    {
+        tokio::spawn(
+            ctx.clone()
+                .handle_cancel(
+                    message.clone(),
+                    bot.clone(),
+                    user.clone(),
+                    c[2].trim().to_string(),
+                )
+                .map(log_error),
+        );
+        return;
+    }
+    let mode;
+    let command;
+    let mut background = false;
+    if let Some((c, m)) = ctx
+        .modes
+        .iter()
+        .find_map(|(pattern, spec)| Some((pattern.captures(raw_text)?, *spec)))
+    {
+        mode = Mode::Custom(m);
+        command = c[2].to_string();
+    } else if let Some(c) = ROOT_COMMAND_PATTERN.captures(raw_text) {
+        mode = Mode::Root;
+        command = preprocessing(&c[2]);
+    } else if let Some(c) = USER_COMMAND_PATTERN.captures(raw_text) {
+        mode = Mode::NonRoot;
+        command = preprocessing(&c[2]);
+    } else if let Some(c) = BG_COMMAND_PATTERN.captures(raw_text) {
+        mode = Mode::NonRoot;
+        command = preprocessing(&c[2]);
+        background = true;
+    } else if message.chat.id.is_user() {
+        mode = Mode::NonRoot;
+        command = preprocessing(raw_text);
+    } else {
+        log::debug!("ignored update: {message:?}");
+        return;
+    }
+    tokio::spawn(
+        ctx.clone()
+            .handle_command(
+                message.clone(),
+                bot.clone(),
+                user.clone(),
+                mode,
+                command,
+                background,
+            )
+            .map(log_error),
+    );
+}
+
 async fn handle_inline_query(
     _ctx: ArcContext,
     _inline_query: InlineQuery,
@@ -230,6 +429,33 @@ async fn handle_inline_query(
     Ok(())
 }
 
+async fn download(bot: &Bot, file: &FileMeta) -> ResponseResult<Vec<u8>> {
+    let file = bot.get_file(file.id.clone()).await?;
+    let mut data = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut data).await?;
+    Ok(data)
+}
+
+/// Text or document of the message replied to, read by shell commands from stdin
+async fn replied_stdin(message: &Message, bot: &Bot) -> ResponseResult<Option<Vec<u8>>> {
+    let Some(replied) = message.reply_to_message() else {
+        return Ok(None);
+    };
+    if let Some(text) = replied.text() {
+        return Ok(Some(text.as_bytes().to_vec()));
+    }
+    match replied.document() {
+        Some(document) => download(bot, &document.file).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Size of the document replied to, which `replied_stdin` downloads
+fn replied_document_size(message: &Message) -> Option<usize> {
+    let document = message.reply_to_message()?.document()?;
+    Some(document.file.size as usize)
+}
+
 fn preprocessing(raw: &str) -> String {
     let mut text = raw.replace('—', "--");
     if !text.ends_with('\n') {
@@ -238,6 +464,105 @@ fn preprocessing(raw: &str) -> String {
     text
 }
 
+fn running_text(id: JobId) -> String {
+    format!("job {id} running...")
+}
+
+fn queue_text(position: usize) -> String {
+    match position {
+        0 => "running...".to_string(),
+        p => format!("queued, position {p}"),
+    }
+}
+
+/// Waits for the permit, keeping the queue position shown in `progress` up to date
+async fn wait_in_queue(queued: Queued, mut shown: usize, bot: &Bot, progress: &Message) -> Permit {
+    let mut position = queued.position();
+    let wait = queued.wait();
+    tokio::pin!(wait);
+    loop {
+        let current = tokio::select! {
+            permit = &mut wait => {
+                if shown != 0 {
+                    let _ = bot
+                        .edit_message_text(progress.chat.id, progress.id, queue_text(0))
+                        .await;
+                }
+                return permit;
+            }
+            Ok(()) = position.changed() => *position.borrow_and_update(),
+        };
+        if current == shown || current == 0 {
+            continue;
+        }
+        shown = current;
+        if let Err(e) = bot
+            .edit_message_text(progress.chat.id, progress.id, queue_text(current))
+            .await
+        {
+            log::debug!("failed to update queue position: {e}");
+        }
+    }
+}
+
+async fn follow_execution(
+    mut execution: Execution,
+    bot: &Bot,
+    progress: &Message,
+) -> Result<ExecutionResult, AceError> {
+    let id = execution.id();
+    if let Err(e) = bot
+        .edit_message_text(progress.chat.id, progress.id, running_text(id))
+        .await
+    {
+        log::debug!("failed to update progress message: {e}");
+    }
+    let mut collector = execution.collector(PROGRESS_LIMIT);
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            event = execution.next() => match event {
+                Some(event) => collector.push(event?),
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if !collector.take_changed() {
+                    continue;
+                }
+                let text = format!(
+                    "{}\n{}",
+                    markdown::escape(&running_text(id)),
+                    markdown::code_block(&collector.live())
+                );
+                if let Err(e) = bot
+                    .edit_message_text(progress.chat.id, progress.id, text)
+                    .parse_mode(ParseMode::MarkdownV2)
+                    .await
+                {
+                    log::debug!("failed to update progress message: {e}");
+                }
+            }
+        }
+    }
+    collector.into_result()
+}
+
+/// Waits for a background job, the result is reported without progress
+async fn wait_background(
+    execution: Execution,
+    bot: &Bot,
+    progress: &Message,
+) -> Result<ExecutionResult, AceError> {
+    let text = format!("job {} running in background", execution.id());
+    if let Err(e) = bot
+        .edit_message_text(progress.chat.id, progress.id, text)
+        .await
+    {
+        log::debug!("failed to update progress message: {e}");
+    }
+    execution.result().await
+}
+
 fn log_error<E: Display>(r: Result<(), E>) {
     if let Err(e) = r {
         log::warn!("error: {e}")
@@ -252,8 +577,92 @@ impl ArcContext {
         user: User,
         mode: Mode,
         command: String,
+        background: bool,
     ) -> ResponseResult<()> {
-        match self.ace.run(mode, &command).await {
+        let (flags, command) = match Flags::parse(&command) {
+            Ok((flags, command)) => (flags, command.to_string()),
+            Err(e) => return report_ace_error(&e, &message, &bot).await,
+        };
+        let stdin = match mode {
+            Mode::NonRoot | Mode::Root => {
+                // checked before the download, as for uploads
+                if let Some(size) = replied_document_size(&message)
+                    && let Err(e) = self.ace.validate_size(size)
+                {
+                    return report_ace_error(&e, &message, &bot).await;
+                }
+                replied_stdin(&message, &bot).await?
+            }
+            _ => None,
+        };
+        let role = match self.is_manager_chat(message.chat.id) {
+            true => Role::Manager,
+            false => Role::Member,
+        };
+        // captions run next to the file they came with
+        let in_inbox = message.document().is_some() || message.photo().is_some();
+        let request = Request {
+            owner: Owner {
+                chat: message.chat.id.to_string(),
+                user: user.id.to_string(),
+            },
+            mode,
+            text: command.clone(),
+            background,
+            timeout: flags.timeout,
+            offline: flags.offline,
+            engine: flags.engine,
+            role,
+            stdin,
+            in_inbox,
+        };
+        if let Err(e) = self.ace.validate(&request) {
+            return report_ace_error(&e, &message, &bot).await;
+        }
+        let queued = self.ace.enqueue(&request);
+        let position = *queued.position().borrow();
+        let progress = bot
+            .send_message(message.chat.id, queue_text(position))
+            .reply_to_message_id(message.id)
+            .await?;
+        let permit = wait_in_queue(queued, position, &bot, &progress).await;
+        let keys = [
+            (message.chat.id, message.id),
+            (progress.chat.id, progress.id),
+        ];
+        let in_session = matches!(mode, Mode::NonRoot)
+            && !background
+            // the network of a running session can not be cut
+            && !request.offline
+            && request.stdin.is_none()
+            && !request.in_inbox
+            && self.ace.has_session(&request.owner.chat);
+        let execution = if in_session {
+            self.ace.run_in_session(&request)
+        } else {
+            self.ace.spawn(&request).await
+        };
+        let result = match execution {
+            Ok(execution) => {
+                let id = execution.id();
+                self.jobs.lock().unwrap().extend(keys.map(|key| (key, id)));
+                if background {
+                    wait_background(execution, &bot, &progress).await
+                } else {
+                    follow_execution(execution, &bot, &progress).await
+                }
+            }
+            Err(e) => Err(e),
+        };
+        drop(permit);
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|key, _| !keys.contains(key));
+        if let Err(e) = bot.delete_message(progress.chat.id, progress.id).await {
+            log::warn!("failed to delete progress message: {e}");
+        }
+        match result {
             Err(e) => report_ace_error(&e, &message, &bot).await,
             Ok(output) => {
                 let output_message =
@@ -264,12 +673,169 @@ impl ArcContext {
         }
     }
 
+    /// Saves the file to the inbox, then runs its caption if it is a command
+    async fn handle_upload(
+        self,
+        message: Message,
+        bot: Bot,
+        user: User,
+        upload: Upload,
+    ) -> ResponseResult<()> {
+        let size = upload.file.size as usize;
+        if let Err(e) = self.ace.validate_upload(&upload.name, size) {
+            return report_ace_error(&e, &message, &bot).await;
+        }
+        let data = download(&bot, &upload.file).await?;
+        match self
+            .ace
+            .upload(&message.chat.id.to_string(), &upload.name, &data)
+            .await
+        {
+            Err(e) => return report_ace_error(&e, &message, &bot).await,
+            Ok(path) => {
+                bot.send_message(message.chat.id, format!("saved to {}", path.display()))
+                    .reply_to_message_id(message.id)
+                    .await?;
+            }
+        }
+        if let Some(caption) = upload.caption.filter(|c| c.starts_with('/')) {
+            handle_text(&self, &message, &bot, &user, &caption);
+        }
+        Ok(())
+    }
+
+    /// Shows the working directory of the chat
+    async fn handle_pwd(self, message: Message, bot: Bot) -> ResponseResult<()> {
+        match self.ace.workdir(&message.chat.id.to_string()) {
+            Err(e) => report_ace_error(&e, &message, &bot).await,
+            Ok(workdir) => {
+                bot.send_message(message.chat.id, workdir.display().to_string())
+                    .reply_to_message_id(message.id)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends a file of the home as a document
+    async fn handle_get(self, message: Message, bot: Bot, argument: String) -> ResponseResult<()> {
+        let (zip, path) = flags::parse_get(&argument);
+        if path.is_empty() {
+            bot.send_message(message.chat.id, "usage: /get [--zip] PATH")
+                .reply_to_message_id(message.id)
+                .await?;
+            return Ok(());
+        }
+        match self.ace.get(&message.chat.id.to_string(), path, zip).await {
+            Err(e) => report_ace_error(&e, &message, &bot).await,
+            Ok(file) => {
+                bot.send_document(
+                    message.chat.id,
+                    InputFile::memory(file.data).file_name(file.name),
+                )
+                .reply_to_message_id(message.id)
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Cancels the job given by id, or the job of the replied message
+    async fn handle_cancel(
+        self,
+        message: Message,
+        bot: Bot,
+        user: User,
+        argument: String,
+    ) -> ResponseResult<()> {
+        let id = if argument.is_empty() {
+            message.reply_to_message().and_then(|replied| {
+                let jobs = self.jobs.lock().unwrap();
+                jobs.get(&(replied.chat.id, replied.id)).copied()
+            })
+        } else {
+            argument.parse().ok()
+        };
+        let Some(id) = id else {
+            bot.send_message(
+                message.chat.id,
+                "usage: reply to a running job, or give its id",
+            )
+            .reply_to_message_id(message.id)
+            .await?;
+            return Ok(());
+        };
+        let requester = Owner {
+            chat: message.chat.id.to_string(),
+            user: user.id.to_string(),
+        };
+        let privileged = self.is_manager_chat(message.chat.id);
+        match self.ace.cancel(id, &requester, privileged).await {
+            Err(e) => report_ace_error(&e, &message, &bot).await,
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Starts a session, later `/user` commands of the chat are fed to it
+    async fn handle_session(self, message: Message, bot: Bot, user: User) -> ResponseResult<()> {
+        let owner = Owner {
+            chat: message.chat.id.to_string(),
+            user: user.id.to_string(),
+        };
+        match self.ace.start_session(&owner).await {
+            Err(e) => report_ace_error(&e, &message, &bot).await,
+            Ok(()) => {
+                bot.send_message(
+                    message.chat.id,
+                    "session started, /user commands run in it until /endsession",
+                )
+                .reply_to_message_id(message.id)
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_end_session(self, message: Message, bot: Bot) -> ResponseResult<()> {
+        match self.ace.end_session(&message.chat.id.to_string()) {
+            Err(e) => report_ace_error(&e, &message, &bot).await,
+            Ok(()) => {
+                bot.send_message(message.chat.id, "session ended")
+                    .reply_to_message_id(message.id)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Lists running jobs of the chat, or of all chats in the manager chat
+    async fn handle_jobs(self, message: Message, bot: Bot) -> ResponseResult<()> {
+        let chat = message.chat.id.to_string();
+        let filter = (!self.is_manager_chat(message.chat.id)).then_some(chat.as_str());
+        let jobs = self.ace.jobs(filter);
+        let text = if jobs.is_empty() {
+            "no running jobs".to_string()
+        } else {
+            jobs.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        bot.send_message(message.chat.id, text)
+            .reply_to_message_id(message.id)
+            .await?;
+        Ok(())
+    }
+
+    fn is_manager_chat(&self, chat: ChatId) -> bool {
+        self.options.manager_chat_id == Some(chat.0)
+    }
+
     async fn handle_reset(self, message: Message, bot: Bot, user: User) -> ResponseResult<()> {
         match self.ace.reset().await {
             Err(e) => report_ace_error(&e, &message, &bot).await,
-            Ok(output) => {
-                let output_message =
-                    OutputMessage::format(self.clone(), &user, None, "/reset", output).await;
+            Ok(()) => {
+                let output_message = OutputMessage::meta(&user, "/reset", "environment reset");
                 self.handle_output(message.chat.id, bot, output_message)
                     .await
             }
@@ -292,16 +858,40 @@ impl ArcContext {
     }
 
     async fn handle_start(self, message: Message, bot: Bot) -> ResponseResult<()> {
+        let modes: String = self
+            .modes
+            .iter()
+            .map(|(_, spec)| {
+                let command = format!("/{}", spec.command);
+                format!("\n    {command:<11} - run the text in {} mode", spec.name)
+            })
+            .collect();
         let help_message = OutputMessage {
-            message: "hello, world
+            message: format!(
+                "hello, world
     ```
-    /user  - run bash commands as a normal user
-    /root  - run bash commands as a root user
-    /reset - reset the whole environment
-    ```"
-            .to_string(),
+    /user       - run bash commands as a normal user
+    /root       - run bash commands as a root user
+    /bg         - run bash commands as a normal user in background
+    /pwd        - show the working directory of this chat
+    /get        - send a file of the home, --zip for directories
+    /jobs       - list running jobs
+    /cancel     - cancel a running job, reply to it or give its id
+    /kill       - same as /cancel
+    /session    - keep a shell for /user commands of this chat
+    /endsession - end the shell of this chat
+    /reset      - reset the whole environment{modes}
+    ```
+    commands may start with --timeout=SECONDS to run longer,
+    with --offline to run without network access,
+    or with --engine=NAME to pick the engine of /dot
+    files written to $ACE_OUT are sent back,
+    and commands replying to a text or file read it from stdin
+    files sent here are saved to the inbox, a command as caption runs afterwards"
+            ),
             photos: Default::default(),
             animations: Default::default(),
+            audios: Default::default(),
             documents: Default::default(),
         };
         help_message.send(&bot, message.chat.id).await?;
@@ -329,6 +919,7 @@ pub struct OutputMessage {
     message: String,
     photos: VecDeque<InputMediaPhoto>,
     animations: VecDeque<InputMediaAnimation>,
+    audios: VecDeque<InputMediaAudio>,
     documents: VecDeque<InputMediaDocument>,
 }
 
@@ -338,19 +929,16 @@ impl OutputMessage {
         user: &User,
         mode: Option<Mode>,
         command: &str,
-        output: Output,
+        output: ExecutionResult,
     ) -> OutputMessage {
-        // TODO wait for https://github.com/teloxide/teloxide/pull/1411
-        let user = match user.mention() {
-            Some(mention) => markdown::escape(&mention),
-            None => markdown::link(user.url().as_str(), &markdown::escape(&user.full_name())),
-        };
+        let user = mention(user);
         const PART_LIMIT: usize = 1000;
         const FILE_LIMIT: usize = 1024 * 1024; // 1 MiB
 
         let mut message = String::new();
         let mut animations = VecDeque::default();
         let mut photos = VecDeque::default();
+        let mut audios = VecDeque::default();
         let mut documents = VecDeque::default();
         let client = reqwest::Client::new();
 
@@ -362,13 +950,7 @@ impl OutputMessage {
         }
         message.push_str(":\n");
         if command.len() < PART_LIMIT {
-            let language = match mode {
-                None => "text",
-                Some(Mode::Nix) => "nix",
-                Some(Mode::Xelatex) => "tex",
-                Some(Mode::Typst) => "typst",
-                Some(Mode::NonRoot) | Some(Mode::Root) => "bash",
-            };
+            let language = mode.as_ref().map_or("text", Mode::language);
             message.push_str(&utils::markdown::code_block_with_lang(
                 command.trim(),
                 language,
@@ -378,7 +960,33 @@ impl OutputMessage {
                 InputFile::memory(Vec::from(command.as_bytes())).file_name("script"),
             ));
         }
-        message.push_str(&format!("{}", output.status));
+        message.push_str(&utils::markdown::escape(&output.summary()));
+        if let Some(diagnostics) = &output.diagnostics
+            && !diagnostics.output.trim_ascii().is_empty()
+        {
+            message.push_str(&format!("\n{}", utils::markdown::escape("(compiler)")));
+            if diagnostics.omitted != 0 {
+                message.push_str(&format!(
+                    "\n{}",
+                    utils::markdown::escape(&format!(
+                        "output truncated, {} bytes omitted",
+                        diagnostics.omitted
+                    ))
+                ));
+            }
+            // collapsed, so that it is not taken for the output of the program
+            match String::from_utf8(diagnostics.output.clone()) {
+                Ok(s) if s.len() < PART_LIMIT => {
+                    message.push_str(&format!("\n{}", expandable_blockquote(&s)));
+                }
+                _ => {
+                    message.push_str("\nattached");
+                    documents.push_back(InputMediaDocument::new(
+                        InputFile::memory(diagnostics.output.clone()).file_name("compiler"),
+                    ));
+                }
+            }
+        }
         if !output.stdout.is_empty() {
             // TODO use mime to support more file formats, e.g. video, audio, etc.
             // let mime = COOKIE.with(|cookie| {
@@ -386,8 +994,13 @@ impl OutputMessage {
             //         .buffer(&output.stdout)
             //         .unwrap_or_else(|_| "application/octet-stream".to_string())
             // });
+            // shell commands may print anything, modes declare their output
+            let kind = mode.and_then(|m| m.output());
+            let name = mode.as_ref().map_or("stdout", Mode::output_name);
             let wand = context.magick_wand();
-            let image = if wand.read_image_blob(&output.stdout).is_ok() {
+            let image = if matches!(kind, None | Some(Output::Image))
+                && wand.read_image_blob(&output.stdout).is_ok()
+            {
                 let frame_num = wand.get_number_images();
                 if frame_num > 1 {
                     wand.write_images_blob("GIF").ok().map(|data| (data, true))
@@ -399,6 +1012,15 @@ impl OutputMessage {
                 None
             };
             message.push_str(&format!("\n{}", utils::markdown::escape("(stdout)")));
+            if output.stdout_omitted != 0 {
+                message.push_str(&format!(
+                    "\n{}",
+                    utils::markdown::escape(&format!(
+                        "output truncated, {} bytes omitted",
+                        output.stdout_omitted
+                    ))
+                ));
+            }
             if let Some((img_data, animated)) = image {
                 if animated {
                     message.push_str("\nanimation attached");
@@ -411,9 +1033,15 @@ impl OutputMessage {
                         InputFile::memory(img_data).file_name("stdout.png"),
                     ));
                 }
+            } else if kind == Some(Output::Audio) {
+                message.push_str("\naudio attached");
+                audios.push_back(InputMediaAudio::new(
+                    InputFile::memory(output.stdout).file_name(name.to_string()),
+                ));
             } else {
                 let mut inlined = false;
-                if let Ok(s) = String::from_utf8(output.stdout.clone())
+                if kind != Some(Output::Document)
+                    && let Ok(s) = String::from_utf8(output.stdout.clone())
                     && s.len() < PART_LIMIT
                 {
                     inlined = true;
@@ -423,12 +1051,12 @@ impl OutputMessage {
                     if output.stdout.len() < FILE_LIMIT {
                         message.push_str("\nattached");
                         if let Ok(cmd) =
-                            pastebin::curl_command(&client, "stdout", output.stdout.clone()).await
+                            pastebin::curl_command(&client, name, output.stdout.clone()).await
                         {
                             message.push_str(&format!("\n{}", utils::markdown::code_block(&cmd)))
                         }
                         documents.push_back(InputMediaDocument::new(
-                            InputFile::memory(output.stdout).file_name("stdout"),
+                            InputFile::memory(output.stdout).file_name(name.to_string()),
                         ));
                     } else {
                         message.push_str("\nfile size limit exceeded");
@@ -439,6 +1067,15 @@ impl OutputMessage {
 
         if !output.stderr.is_empty() {
             message.push_str(&format!("\n{}", utils::markdown::escape("(stderr)")));
+            if output.stderr_omitted != 0 {
+                message.push_str(&format!(
+                    "\n{}",
+                    utils::markdown::escape(&format!(
+                        "output truncated, {} bytes omitted",
+                        output.stderr_omitted
+                    ))
+                ));
+            }
             let mut inlined = false;
             if let Ok(s) = String::from_utf8(output.stderr.clone())
                 && s.len() < PART_LIMIT
@@ -461,14 +1098,64 @@ impl OutputMessage {
             }
         }
 
+        let artifacts = output.artifacts;
+        if !artifacts.files.is_empty() || artifacts.omitted != 0 {
+            message.push_str(&format!("\n{}", utils::markdown::escape("(artifacts)")));
+            let names: Vec<_> = artifacts.files.iter().map(|a| a.name.as_str()).collect();
+            if !names.is_empty() {
+                message.push_str(&format!("\n{}", utils::markdown::escape(&names.join(", "))));
+            }
+            if artifacts.omitted != 0 {
+                message.push_str(&format!(
+                    "\n{}",
+                    utils::markdown::escape(&format!(
+                        "{} files omitted, artifacts are limited in number and size",
+                        artifacts.omitted
+                    ))
+                ));
+            }
+            // modes only say what their artifacts mostly are, e.g. figures
+            // besides a csv file, each one is checked by its content
+            let kind = mode.and_then(|m| m.artifacts());
+            for artifact in artifacts.files {
+                let mime = mime_type(&artifact.data);
+                let file = InputFile::memory(artifact.data).file_name(artifact.name);
+                match kind {
+                    Some(Output::Image) if PHOTO_TYPES.contains(&mime.as_str()) => {
+                        photos.push_back(InputMediaPhoto::new(file))
+                    }
+                    Some(Output::Audio) if AUDIO_TYPES.contains(&mime.as_str()) => {
+                        audios.push_back(InputMediaAudio::new(file))
+                    }
+                    _ => documents.push_back(InputMediaDocument::new(file)),
+                }
+            }
+        }
+
         OutputMessage {
             message,
             animations,
             photos,
+            audios,
             documents,
         }
     }
 
+    /// A meta command finished without output of its own
+    fn meta(user: &User, command: &str, text: &str) -> OutputMessage {
+        let mut message = mention(user);
+        message.push_str(&markdown::escape(" (meta):\n"));
+        message.push_str(&markdown::code_block_with_lang(command, "text"));
+        message.push_str(&markdown::escape(text));
+        OutputMessage {
+            message,
+            photos: Default::default(),
+            animations: Default::default(),
+            audios: Default::default(),
+            documents: Default::default(),
+        }
+    }
+
     async fn send(mut self, bot: &Bot, chat_id: ChatId) -> ResponseResult<()> {
         let mut last_msg = None;
 
@@ -495,51 +1182,35 @@ impl OutputMessage {
             );
         }
 
-        if !self.photos.is_empty() {
-            if last_msg.is_none() {
-                let first = self
-                    .photos
-                    .pop_front()
-                    .unwrap()
-                    .caption(self.message.clone())
-                    .parse_mode(ParseMode::MarkdownV2);
-                self.photos.push_front(first);
-            }
-            let media = self.photos.into_iter().map(InputMedia::Photo);
-            let send = bot.send_media_group(chat_id, media);
-            last_msg = Some(
-                (match last_msg {
-                    Some(msg) => send.reply_to_message_id(msg.id),
-                    None => send,
-                })
-                .await?
+        // media groups can not mix photos, audio and documents
+        let groups: [Vec<_>; 3] = [
+            self.photos.into_iter().map(InputMedia::Photo).collect(),
+            self.audios.into_iter().map(InputMedia::Audio).collect(),
+            self.documents
                 .into_iter()
-                .next()
-                .expect("empty media group response"),
-            );
-        }
-        if !self.documents.is_empty() {
-            if last_msg.is_none() {
-                let first = self
-                    .documents
-                    .pop_front()
-                    .unwrap()
-                    .caption(self.message.clone())
-                    .parse_mode(ParseMode::MarkdownV2);
-                self.documents.push_front(first);
+                .map(InputMedia::Document)
+                .collect(),
+        ];
+        for mut media in groups {
+            while !media.is_empty() {
+                let n = media.len().min(MEDIA_GROUP_LIMIT);
+                let mut group: Vec<_> = media.drain(..n).collect();
+                if last_msg.is_none() {
+                    let first = group.remove(0);
+                    group.insert(0, with_caption(first, self.message.clone()));
+                }
+                let send = bot.send_media_group(chat_id, group);
+                last_msg = Some(
+                    (match last_msg {
+                        Some(msg) => send.reply_to_message_id(msg.id),
+                        None => send,
+                    })
+                    .await?
+                    .into_iter()
+                    .next()
+                    .expect("empty media group response"),
+                );
             }
-            let media = self.documents.into_iter().map(InputMedia::Document);
-            let send = bot.send_media_group(chat_id, media);
-            last_msg = Some(
-                (match last_msg {
-                    Some(msg) => send.reply_to_message_id(msg.id),
-                    None => send,
-                })
-                .await?
-                .into_iter()
-                .next()
-                .expect("empty media group response"),
-            );
         }
         if last_msg.is_none() {
             bot.send_message(chat_id, self.message)
@@ -550,6 +1221,41 @@ impl OutputMessage {
     }
 }
 
+/// Markdown mentioning the user
+fn mention(user: &User) -> String {
+    // TODO wait for https://github.com/teloxide/teloxide/pull/1411
+    match user.mention() {
+        Some(mention) => markdown::escape(&mention),
+        None => markdown::link(user.url().as_str(), &markdown::escape(&user.full_name())),
+    }
+}
+
+/// Quotes `text` in a block which is collapsed until tapped
+fn expandable_blockquote(text: &str) -> String {
+    let lines: Vec<_> = text
+        .trim_end()
+        .lines()
+        .map(|line| format!(">{}", markdown::escape(line)))
+        .collect();
+    format!("**{}||", lines.join("\n"))
+}
+
+/// Sets the caption of media sent in groups, the message of an output
+fn with_caption(media: InputMedia, caption: String) -> InputMedia {
+    match media {
+        InputMedia::Photo(m) => {
+            InputMedia::Photo(m.caption(caption).parse_mode(ParseMode::MarkdownV2))
+        }
+        InputMedia::Audio(m) => {
+            InputMedia::Audio(m.caption(caption).parse_mode(ParseMode::MarkdownV2))
+        }
+        InputMedia::Document(m) => {
+            InputMedia::Document(m.caption(caption).parse_mode(ParseMode::MarkdownV2))
+        }
+        media => media,
+    }
+}
+
 pub async fn report_ace_error(
     err: &AceError,
     msg: &Message,
@@ -0,0 +1,190 @@
+use crate::execution::{Event, EventSender, Execution, Exit, Usage};
+use crate::executor::Invocation;
+use crate::hardening::Hardening;
+use crate::jobs::JobGuard;
+use crate::tasks::TaskDir;
+use crate::{AceError, Mode, Options};
+use ace_bot_agent::protocol::{self, Frame, Limits, Spawn, read_frame, write_frame};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UnixStream;
+use tokio::sync::Notify;
+
+const CHUNK_SIZE: usize = 65536;
+
+/// Client of `ace-bot-agent` running inside the machine
+///
+/// Commands run by the agent skip starting a transient unit each. The agent
+/// does not sandbox commands, so modes with a hardening profile skip it.
+#[derive(Debug)]
+pub(crate) struct Agent {
+    socket: PathBuf,
+    shell: String,
+    user: String,
+    group: String,
+    guest_home: PathBuf,
+    non_root_hardened: bool,
+    root_hardened: bool,
+}
+
+impl Agent {
+    pub(crate) fn new(options: &Options, socket: PathBuf) -> Self {
+        Self {
+            socket,
+            shell: options.shell.clone(),
+            user: options.user_mode_user.clone(),
+            group: options.user_mode_group.clone(),
+            guest_home: options.user_guest_home.clone(),
+            non_root_hardened: hardened(options, Mode::NonRoot),
+            root_hardened: hardened(options, Mode::Root),
+        }
+    }
+
+    /// Connects to the agent, returns `None` if it is not available
+    pub(crate) async fn connect(&self) -> Option<UnixStream> {
+        match UnixStream::connect(&self.socket).await {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                log::debug!("agent not available: {e}");
+                None
+            }
+        }
+    }
+
+    /// Builds the spawn frame, returns `None` if the mode has a hardening profile
+    pub(crate) fn spawn_frame(&self, invocation: &Invocation) -> Result<Option<Spawn>, AceError> {
+        let (user, group, working_directory, hardened) = match invocation.mode {
+            Mode::NonRoot => (
+                self.user.clone(),
+                self.group.clone(),
+                self.guest_home.clone(),
+                self.non_root_hardened,
+            ),
+            Mode::Root => (
+                "root".to_string(),
+                "root".to_string(),
+                "/root".into(),
+                self.root_hardened,
+            ),
+            mode => return Err(AceError::InvalidMode(mode)),
+        };
+        if hardened {
+            return Ok(None);
+        }
+        Ok(Some(Spawn {
+            user,
+            group,
+            shell: self.shell.clone(),
+            working_directory,
+            timeout: invocation.timeout as u64,
+            limits: Limits {
+                memory_max: invocation.limits.memory_max,
+                cpu_quota: invocation.limits.cpu_quota,
+                tasks_max: invocation.limits.tasks_max,
+                offline: invocation.limits.offline,
+            },
+        }))
+    }
+}
+
+/// Whether the hardening profile of `mode` has any effect, the agent does not
+/// enforce it
+fn hardened(options: &Options, mode: Mode) -> bool {
+    let hardening = Hardening::of(mode, &options.user_guest_home, &options.hardening);
+    if hardening.is_empty() {
+        return false;
+    }
+    log::info!("commands in {mode} mode skip the agent, their units are hardened");
+    true
+}
+
+/// Runs `spawn` on the connection, stopping the command once `kill` is notified
+///
+/// `job` and `task_dir` are kept alive until the command exits.
+pub(crate) fn execute(
+    stream: UnixStream,
+    spawn: Spawn,
+    input: Vec<u8>,
+    kill: Arc<Notify>,
+    job: JobGuard,
+    task_dir: TaskDir,
+    output_limit: usize,
+) -> Execution {
+    let (sender, execution) = Execution::channel(job.id(), output_limit);
+    let start = Instant::now();
+    tokio::spawn(async move {
+        match forward(stream, spawn, input, kill, &sender).await {
+            Ok(Some(exit)) => {
+                let exit = Exit {
+                    status: exit.status(),
+                    duration: start.elapsed(),
+                    usage: Usage {
+                        cpu_time: exit.cpu_time.map(Duration::from_micros),
+                        memory_peak: exit.memory_peak,
+                        timed_out: exit.timed_out,
+                        oom_killed: exit.oom_killed,
+                        ..Default::default()
+                    },
+                    cancelled: job.is_cancelled(),
+                    artifacts: task_dir.artifacts(),
+                    diagnostics: task_dir.diagnostics(),
+                };
+                let _ = sender.send(Ok(Event::Exit(exit))).await;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                let _ = sender.send(Err(e)).await;
+            }
+        }
+        drop(job);
+        drop(task_dir);
+    });
+    execution
+}
+
+/// Forwards the output of the command, returns `None` if the execution is dropped
+async fn forward(
+    stream: UnixStream,
+    spawn: Spawn,
+    input: Vec<u8>,
+    kill: Arc<Notify>,
+    sender: &EventSender,
+) -> Result<Option<protocol::Exit>, AceError> {
+    let (mut reader, mut writer) = stream.into_split();
+    write_frame(&mut writer, &Frame::Spawn(spawn)).await?;
+    // the agent reads frames independently of the output, so writing them
+    // here can not block the output
+    let control = tokio::spawn(async move {
+        for chunk in input.chunks(CHUNK_SIZE) {
+            write_frame(&mut writer, &Frame::Stdin(chunk.to_vec())).await?;
+        }
+        write_frame(&mut writer, &Frame::StdinEnd).await?;
+        kill.notified().await;
+        write_frame(&mut writer, &Frame::Kill).await?;
+        // closing the connection would count as being dropped
+        std::future::pending::<()>().await;
+        Ok::<_, protocol::Error>(())
+    });
+    let result = async {
+        loop {
+            let event = match read_frame(&mut reader).await? {
+                Some(Frame::Stdout(data)) => Event::Stdout(data),
+                Some(Frame::Stderr(data)) => Event::Stderr(data),
+                Some(Frame::Exit(exit)) => return Ok(Some(exit)),
+                Some(Frame::Error(message)) => return Err(AceError::AgentFailed(message)),
+                Some(frame) => {
+                    return Err(protocol::Error::UnexpectedFrame(frame.name()).into());
+                }
+                None => return Err(AceError::AgentDisconnected),
+            };
+            if sender.send(Ok(event)).await.is_err() {
+                // the agent kills the command once the connection is closed
+                return Ok(None);
+            }
+        }
+    }
+    .await;
+    control.abort();
+    result
+}
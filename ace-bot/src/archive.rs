@@ -0,0 +1,82 @@
+//! Zip archives of directories in the home of the guest user
+
+use crate::tasks::{file_type, list_dir, open_nofollow, read_file};
+use rustix::fs::FileType;
+use rustix::io::Errno;
+use std::ffi::CString;
+use std::io::{self, Cursor, Write};
+use std::os::fd::OwnedFd;
+use std::vec;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+/// Zips the directory, failing once more than `limit` bytes are read
+///
+/// Only regular files and directories are archived, symlinks are skipped
+/// rather than followed.
+pub fn zip_dir(dir: OwnedFd, limit: usize) -> Result<Vec<u8>, io::Error> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut remaining = limit;
+    add_dir(&mut writer, dir, "", &mut remaining)?;
+    let data = writer.finish().map_err(io::Error::other)?.into_inner();
+    if data.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "size limit exceeded",
+        ));
+    }
+    Ok(data)
+}
+
+/// Adds the content of `dir` under `prefix`
+///
+/// Directories being archived are kept on a stack rather than the call stack,
+/// the guest decides how deep the tree is.
+fn add_dir(
+    writer: &mut ZipWriter<Cursor<Vec<u8>>>,
+    dir: OwnedFd,
+    prefix: &str,
+    remaining: &mut usize,
+) -> Result<(), io::Error> {
+    let options = SimpleFileOptions::default();
+    // directories with the names left in them and their paths in the archive
+    let names = sorted_names(&dir)?;
+    let mut stack = vec![(dir, names, prefix.to_string())];
+    while let Some((dir, names, prefix)) = stack.last_mut() {
+        let Some(name) = names.next() else {
+            stack.pop();
+            continue;
+        };
+        let fd = match open_nofollow(&*dir, name.as_c_str()) {
+            Ok(fd) => fd,
+            Err(Errno::LOOP) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let path = format!("{prefix}{}", name.to_string_lossy());
+        match file_type(&fd)? {
+            FileType::Directory => {
+                writer
+                    .add_directory(path.as_str(), options)
+                    .map_err(io::Error::other)?;
+                let names = sorted_names(&fd)?;
+                stack.push((fd, names, format!("{path}/")));
+            }
+            FileType::RegularFile => {
+                let data = read_file(fd, *remaining)?;
+                *remaining -= data.len();
+                writer
+                    .start_file(path.as_str(), options)
+                    .map_err(io::Error::other)?;
+                writer.write_all(&data)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn sorted_names(dir: &OwnedFd) -> Result<vec::IntoIter<CString>, io::Error> {
+    let mut names = list_dir(dir)?;
+    names.sort();
+    Ok(names.into_iter())
+}
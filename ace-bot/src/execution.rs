@@ -0,0 +1,460 @@
+use crate::AceError;
+use crate::jobs::{JobGuard, JobId};
+use crate::tasks::TaskDir;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::os::unix::process::ExitStatusExt;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{io, mem};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Child;
+use tokio::sync::mpsc;
+
+const CHUNK_SIZE: usize = 8192;
+const CHANNEL_CAPACITY: usize = 16;
+
+pub(crate) type EventSender = mpsc::Sender<Result<Event, AceError>>;
+
+#[derive(Debug)]
+pub enum Event {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exit(Exit),
+}
+
+#[derive(Clone, Debug)]
+pub struct Exit {
+    pub status: ExitStatus,
+    /// wall-clock time from spawning to exiting
+    pub duration: Duration,
+    pub usage: Usage,
+    pub cancelled: bool,
+    pub artifacts: Artifacts,
+    pub diagnostics: Option<Diagnostics>,
+}
+
+/// Output of the compiler of a compiled mode, kept apart from the program's
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+    pub output: Vec<u8>,
+    /// bytes dropped from the end of the output
+    pub omitted: usize,
+    /// the compiler failed, so the program did not run
+    pub failed: bool,
+}
+
+/// Files left in `$ACE_OUT` by the command, sorted by name
+#[derive(Clone, Debug, Default)]
+pub struct Artifacts {
+    pub files: Vec<Artifact>,
+    /// number of files dropped for exceeding the artifact limits
+    pub omitted: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Artifact {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Accounting of an execution, as far as the executor knows it
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    /// name of the transient unit running the command
+    pub unit: Option<String>,
+    pub cpu_time: Option<Duration>,
+    /// peak memory in bytes
+    pub memory_peak: Option<u64>,
+    pub timed_out: bool,
+    pub oom_killed: bool,
+}
+
+/// Watches a command on behalf of its executor
+pub trait Monitor: Send {
+    /// Filters the stderr of the command, e.g. removing messages of the executor itself
+    fn stderr(&mut self, data: Vec<u8>) -> Vec<u8> {
+        data
+    }
+
+    /// Returns the remaining stderr and the usage once the command exits
+    fn finish(self: Box<Self>, status: &ExitStatus) -> (Vec<u8>, Usage);
+}
+
+/// A running command, streaming its output as [`Event`]s
+///
+/// The last event of a successful execution is always [`Event::Exit`].
+/// Dropping the execution kills the command.
+#[derive(Debug)]
+pub struct Execution {
+    id: JobId,
+    receiver: mpsc::Receiver<Result<Event, AceError>>,
+    output_limit: usize,
+}
+
+impl Execution {
+    /// Creates an execution receiving the events sent through the returned sender
+    pub(crate) fn channel(id: JobId, output_limit: usize) -> (EventSender, Self) {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let execution = Self {
+            id,
+            receiver,
+            output_limit,
+        };
+        (sender, execution)
+    }
+
+    /// Feeds `input` to the child and forwards its output
+    ///
+    /// `job` and `task_dir` are kept alive until the child exits. At most
+    /// `output_limit` bytes of each stream are captured by collectors of the
+    /// execution.
+    pub(crate) fn spawn(
+        mut child: Child,
+        input: Vec<u8>,
+        monitor: Box<dyn Monitor>,
+        job: JobGuard,
+        task_dir: TaskDir,
+        output_limit: usize,
+    ) -> Self {
+        let (sender, execution) = Self::channel(job.id(), output_limit);
+        let start = Instant::now();
+        tokio::spawn(async move {
+            match forward(&mut child, input, monitor, start, &sender).await {
+                Ok(Some(mut exit)) => {
+                    exit.cancelled = job.is_cancelled();
+                    exit.artifacts = task_dir.artifacts();
+                    exit.diagnostics = task_dir.diagnostics();
+                    let _ = sender.send(Ok(Event::Exit(exit))).await;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                }
+            }
+            drop(job);
+            drop(task_dir);
+        });
+        execution
+    }
+
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Creates a collector respecting the output limit of the execution
+    pub fn collector(&self, live_limit: usize) -> OutputCollector {
+        OutputCollector::new(self.output_limit, live_limit)
+    }
+
+    /// Waits for the command to exit, collecting its output
+    pub async fn result(mut self) -> Result<ExecutionResult, AceError> {
+        let mut collector = self.collector(0);
+        while let Some(event) = self.receiver.recv().await {
+            collector.push(event?);
+        }
+        collector.into_result()
+    }
+}
+
+impl Stream for Execution {
+    type Item = Result<Event, AceError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Forwards the output of the child, returns `None` if the execution is dropped
+async fn forward(
+    child: &mut Child,
+    input: Vec<u8>,
+    mut monitor: Box<dyn Monitor>,
+    start: Instant,
+    sender: &EventSender,
+) -> Result<Option<Exit>, AceError> {
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let forwarded = forward_output(stdin, stdout, stderr, input, sender, |data| {
+        monitor.stderr(data)
+    })
+    .await?;
+    if !forwarded {
+        // the child is killed on drop
+        return Ok(None);
+    }
+    let status = child.wait().await?;
+    let duration = start.elapsed();
+    let (rest, usage) = monitor.finish(&status);
+    if !rest.is_empty() && sender.send(Ok(Event::Stderr(rest))).await.is_err() {
+        return Ok(None);
+    }
+    Ok(Some(Exit {
+        status,
+        duration,
+        usage,
+        cancelled: false,
+        artifacts: Default::default(),
+        diagnostics: None,
+    }))
+}
+
+/// Writes `input` to `stdin` and sends the output until both streams end
+///
+/// `filter` is applied to stderr. Returns `false` if the execution is dropped.
+pub(crate) async fn forward_output(
+    mut stdin: impl AsyncWrite + Unpin,
+    mut stdout: impl AsyncRead + Unpin,
+    mut stderr: impl AsyncRead + Unpin,
+    input: Vec<u8>,
+    sender: &EventSender,
+    mut filter: impl FnMut(Vec<u8>) -> Vec<u8>,
+) -> Result<bool, AceError> {
+    let write = async move {
+        stdin.write_all(&input).await?;
+        stdin.flush().await
+    };
+    tokio::pin!(write);
+    let mut writing = true;
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+    let mut stdout_buffer = vec![0; CHUNK_SIZE];
+    let mut stderr_buffer = vec![0; CHUNK_SIZE];
+    while stdout_open || stderr_open {
+        let event = tokio::select! {
+            result = &mut write, if writing => {
+                writing = false;
+                match result {
+                    // the shell may exit without reading the whole script
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+                    _ => continue,
+                }
+            }
+            n = stdout.read(&mut stdout_buffer), if stdout_open => match n? {
+                0 => {
+                    stdout_open = false;
+                    continue;
+                }
+                n => Event::Stdout(stdout_buffer[..n].to_vec()),
+            },
+            n = stderr.read(&mut stderr_buffer), if stderr_open => match n? {
+                0 => {
+                    stderr_open = false;
+                    continue;
+                }
+                n => match filter(stderr_buffer[..n].to_vec()) {
+                    data if data.is_empty() => continue,
+                    data => Event::Stderr(data),
+                },
+            },
+        };
+        if sender.send(Ok(event)).await.is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Result of a finished execution
+///
+/// `stdout` and `stderr` only keep the head and the tail of each stream when
+/// it exceeds the output limit, the number of bytes dropped in between is
+/// recorded in `stdout_omitted` and `stderr_omitted`.
+#[derive(Clone, Debug)]
+pub struct ExecutionResult {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub stdout_omitted: usize,
+    pub stderr_omitted: usize,
+    pub duration: Duration,
+    pub usage: Usage,
+    pub cancelled: bool,
+    pub artifacts: Artifacts,
+    pub diagnostics: Option<Diagnostics>,
+}
+
+impl ExecutionResult {
+    pub fn is_truncated(&self) -> bool {
+        self.stdout_omitted != 0 || self.stderr_omitted != 0
+    }
+
+    /// Whether the compiler of a compiled mode failed, rather than the program
+    pub fn compile_failed(&self) -> bool {
+        self.diagnostics.as_ref().is_some_and(|d| d.failed)
+    }
+
+    /// One line summary, e.g. `exit 0 · 1.3s · 42 MiB`
+    pub fn summary(&self) -> String {
+        let status = if self.cancelled {
+            "cancelled".to_string()
+        } else if self.usage.timed_out {
+            format!("timed out after {}s", self.duration.as_secs_f64().round())
+        } else if self.usage.oom_killed {
+            "killed, out of memory".to_string()
+        } else if let Some(code) = self.status.code() {
+            format!("exit {code}")
+        } else if let Some(signal) = self.status.signal() {
+            format!("killed by signal {signal}")
+        } else {
+            format!("{}", self.status)
+        };
+        let status = match self.compile_failed() {
+            true => format!("compile failed, {status}"),
+            false => status,
+        };
+        let mut parts = vec![status];
+        if !self.usage.timed_out {
+            parts.push(format!("{:.1}s", self.duration.as_secs_f64()));
+        }
+        if let Some(memory) = self.usage.memory_peak {
+            parts.push(format_bytes(memory));
+        }
+        parts.join(" · ")
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = u;
+    }
+    if value < 10.0 {
+        format!("{value:.1} {unit}")
+    } else {
+        format!("{value:.0} {unit}")
+    }
+}
+
+/// Bytes of a stream, keeping at most `limit` bytes from its head and tail
+#[derive(Debug, Default)]
+struct Capture {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    limit: usize,
+    omitted: usize,
+}
+
+impl Capture {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    fn push(&mut self, mut data: &[u8]) {
+        let head_limit = self.limit / 2;
+        if self.head.len() < head_limit {
+            let n = (head_limit - self.head.len()).min(data.len());
+            self.head.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+        self.tail.extend(data);
+        let tail_limit = self.limit - head_limit;
+        if self.tail.len() > tail_limit {
+            let excess = self.tail.len() - tail_limit;
+            self.tail.drain(..excess);
+            self.omitted += excess;
+        }
+    }
+
+    fn into_parts(self) -> (Vec<u8>, usize) {
+        let mut data = self.head;
+        data.extend(self.tail);
+        (data, self.omitted)
+    }
+}
+
+/// Accumulates the events of an [`Execution`]
+///
+/// Besides the bounded capture of each stream, the last `live_limit` bytes of
+/// stdout and stderr are kept interleaved for progress reports.
+#[derive(Debug)]
+pub struct OutputCollector {
+    stdout: Capture,
+    stderr: Capture,
+    exit: Option<Exit>,
+    live: Vec<u8>,
+    live_limit: usize,
+    changed: bool,
+}
+
+impl OutputCollector {
+    pub fn new(output_limit: usize, live_limit: usize) -> Self {
+        Self {
+            stdout: Capture::new(output_limit),
+            stderr: Capture::new(output_limit),
+            exit: None,
+            live: Vec::new(),
+            live_limit,
+            changed: false,
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        match event {
+            Event::Stdout(data) => {
+                self.push_live(&data);
+                self.stdout.push(&data);
+            }
+            Event::Stderr(data) => {
+                self.push_live(&data);
+                self.stderr.push(&data);
+            }
+            Event::Exit(exit) => self.exit = Some(exit),
+        }
+    }
+
+    fn push_live(&mut self, data: &[u8]) {
+        if self.live_limit == 0 {
+            return;
+        }
+        self.live.extend_from_slice(data);
+        if self.live.len() > self.live_limit {
+            let excess = self.live.len() - self.live_limit;
+            self.live.drain(..excess);
+        }
+        self.changed = true;
+    }
+
+    /// Tail of the interleaved stdout and stderr
+    pub fn live(&self) -> String {
+        String::from_utf8_lossy(&self.live).into_owned()
+    }
+
+    /// Whether new output arrived since the last call
+    pub fn take_changed(&mut self) -> bool {
+        mem::take(&mut self.changed)
+    }
+
+    pub fn into_result(self) -> Result<ExecutionResult, AceError> {
+        let exit = self.exit.ok_or(AceError::MissingExitStatus)?;
+        let (stdout, stdout_omitted) = self.stdout.into_parts();
+        let (stderr, stderr_omitted) = self.stderr.into_parts();
+        Ok(ExecutionResult {
+            status: exit.status,
+            stdout,
+            stderr,
+            stdout_omitted,
+            stderr_omitted,
+            duration: exit.duration,
+            usage: exit.usage,
+            cancelled: exit.cancelled,
+            artifacts: exit.artifacts,
+            diagnostics: exit.diagnostics,
+        })
+    }
+}
@@ -0,0 +1,382 @@
+use crate::execution::{Monitor, Usage};
+use crate::hardening::Hardening;
+use crate::resources::ResourceLimits;
+use crate::systemd::TransientUnit;
+use crate::{AceError, Mode, Options};
+use clap::ValueEnum;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExecutorKind {
+    /// run commands in the machine as transient units, started over D-Bus
+    #[default]
+    #[value(alias = "systemd-run")]
+    Systemd,
+    /// run commands directly on the host as the bot user, for development only
+    Local,
+    /// run commands on the host inside a bubblewrap sandbox
+    Bubblewrap,
+}
+
+#[derive(Clone, Debug)]
+pub struct Invocation {
+    pub mode: Mode,
+    pub timeout: usize,
+    /// limits besides the timeout, see `ResourceLimits` for what is enforced
+    pub limits: ResourceLimits,
+    /// unique name of the invocation, used as the transient unit name
+    pub name: String,
+}
+
+/// How an invocation running `shell --login` is started
+///
+/// The script is written to the stdin of the shell.
+pub enum Launch {
+    /// spawned on the host, watched by the monitor
+    Command {
+        command: Command,
+        monitor: Box<dyn Monitor>,
+    },
+    /// started by systemd, which reports the result itself
+    Unit(TransientUnit),
+}
+
+pub trait Executor: fmt::Debug + Send + Sync {
+    fn launch(&self, invocation: &Invocation) -> Result<Launch, AceError>;
+
+    /// Builds the command running an interactive shell on the terminal it is
+    /// attached to, without any timeout
+    fn session(&self, invocation: &Invocation) -> Result<Command, AceError>;
+
+    /// Builds the command stopping a running invocation
+    ///
+    /// `pid` is the process id of the command built for the invocation.
+    fn cancel(&self, invocation: &Invocation, pid: Option<u32>) -> Option<Command>;
+}
+
+/// Fails if `limits` has a limit the executor does not enforce
+///
+/// Executors running commands on the host only enforce the timeout, and cut
+/// network access if `cuts_network` is set.
+fn check_limits(limits: &ResourceLimits, cuts_network: bool) -> Result<(), AceError> {
+    let unenforced = [
+        ("memory-max", limits.memory_max.is_some()),
+        ("cpu-quota", limits.cpu_quota.is_some()),
+        ("tasks-max", limits.tasks_max.is_some()),
+        ("offline", limits.offline && !cuts_network),
+    ];
+    match unenforced.into_iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(AceError::UnenforcedLimit(name)),
+        None => Ok(()),
+    }
+}
+
+/// Sends SIGTERM to the command itself, used by executors running it on the host
+fn kill(pid: Option<u32>) -> Option<Command> {
+    let mut command = Command::new("kill");
+    command.args(["-TERM", &pid?.to_string()]);
+    Some(command)
+}
+
+pub fn from_options(options: &Options, uid: u32, gid: u32) -> Box<dyn Executor> {
+    match options.executor {
+        ExecutorKind::Systemd => Box::new(Systemd::new(options)),
+        ExecutorKind::Local => Box::new(Local::new(options)),
+        ExecutorKind::Bubblewrap => Box::new(Bubblewrap::new(options, uid, gid)),
+    }
+}
+
+/// Runs commands as transient units of the machine
+///
+/// The machine needs systemd 254 or newer, which knows the `AddRef` property
+/// of transient units. Sessions need a terminal and still go through
+/// `systemd-run --pty`.
+#[derive(Debug)]
+pub struct Systemd {
+    machine: String,
+    shell: String,
+    user: String,
+    group: String,
+    guest_home: PathBuf,
+    non_root_hardening: Hardening,
+    root_hardening: Hardening,
+}
+
+impl Systemd {
+    pub fn new(options: &Options) -> Self {
+        let hardening = |mode| Hardening::of(mode, &options.user_guest_home, &options.hardening);
+        Self {
+            machine: options.machine.clone(),
+            shell: options.shell.clone(),
+            user: options.user_mode_user.clone(),
+            group: options.user_mode_group.clone(),
+            guest_home: options.user_guest_home.clone(),
+            non_root_hardening: hardening(Mode::NonRoot),
+            root_hardening: hardening(Mode::Root),
+        }
+    }
+
+    /// Sandboxing properties of the units of `mode`
+    fn hardening(&self, mode: Mode) -> Result<&Hardening, AceError> {
+        match mode {
+            Mode::NonRoot => Ok(&self.non_root_hardening),
+            Mode::Root => Ok(&self.root_hardening),
+            mode => Err(AceError::InvalidMode(mode)),
+        }
+    }
+
+    /// Adds the user and the working directory of the mode
+    fn identity(&self, command: &mut Command, mode: Mode) -> Result<(), AceError> {
+        match mode {
+            Mode::NonRoot => {
+                command.args([
+                    // systemd-run accepts user/group names
+                    &format!("--uid={}", self.user),
+                    &format!("--gid={}", self.group),
+                ]);
+                command.arg("--working-directory");
+                command.arg(&self.guest_home);
+            }
+            Mode::Root => {
+                command.args(["--working-directory=/root"]);
+            }
+            mode => return Err(AceError::InvalidMode(mode)),
+        }
+        Ok(())
+    }
+}
+
+impl Executor for Systemd {
+    fn launch(&self, invocation: &Invocation) -> Result<Launch, AceError> {
+        let (user, group, working_directory) = match invocation.mode {
+            Mode::NonRoot => (
+                Some(self.user.clone()),
+                Some(self.group.clone()),
+                self.guest_home.clone(),
+            ),
+            Mode::Root => (None, None, "/root".into()),
+            mode => return Err(AceError::InvalidMode(mode)),
+        };
+        Ok(Launch::Unit(TransientUnit {
+            machine: self.machine.clone(),
+            name: format!("{}.service", invocation.name),
+            user,
+            group,
+            working_directory,
+            argv: vec![self.shell.clone(), "--login".to_string()],
+            timeout: Duration::from_secs(invocation.timeout as u64),
+            limits: invocation.limits,
+            hardening: self.hardening(invocation.mode)?.clone(),
+        }))
+    }
+
+    fn session(&self, invocation: &Invocation) -> Result<Command, AceError> {
+        let mut command = Command::new("systemd-run");
+        command.args([
+            &format!("--machine={}", self.machine),
+            &format!("--unit={}", invocation.name),
+            "--collect",
+            "--quiet",
+            "--pty",
+            "--send-sighup",
+        ]);
+        let limits = &invocation.limits;
+        if let Some(bytes) = limits.memory_max {
+            command.arg(format!("--property=MemoryMax={bytes}"));
+        }
+        if let Some(percent) = limits.cpu_quota {
+            command.arg(format!("--property=CPUQuota={percent}%"));
+        }
+        if let Some(tasks) = limits.tasks_max {
+            command.arg(format!("--property=TasksMax={tasks}"));
+        }
+        if limits.offline {
+            command.args([
+                "--property=PrivateNetwork=yes",
+                "--property=IPAddressDeny=any",
+            ]);
+        }
+        command.args(self.hardening(invocation.mode)?.arguments());
+        self.identity(&mut command, invocation.mode)?;
+        command.arg("--").args([&self.shell, "--login"]);
+        Ok(command)
+    }
+
+    fn cancel(&self, invocation: &Invocation, _pid: Option<u32>) -> Option<Command> {
+        // killing systemd-run would leave the unit running
+        let mut command = Command::new("systemctl");
+        command.args([
+            &format!("--machine={}", self.machine),
+            "stop",
+            &format!("{}.service", invocation.name),
+        ]);
+        Some(command)
+    }
+}
+
+/// Detects timeouts of commands wrapped in coreutils `timeout`
+///
+/// The wrapper exits with 124 after SIGTERM and 137 after SIGKILL from
+/// `--kill-after`, but so can the command itself. Only exits after the limit
+/// has passed count as timeouts.
+struct TimeoutMonitor {
+    timeout: Duration,
+    start: Instant,
+}
+
+impl TimeoutMonitor {
+    fn new(invocation: &Invocation) -> Self {
+        Self {
+            timeout: Duration::from_secs(invocation.timeout as u64),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Monitor for TimeoutMonitor {
+    fn finish(self: Box<Self>, status: &ExitStatus) -> (Vec<u8>, Usage) {
+        let usage = Usage {
+            timed_out: matches!(status.code(), Some(124) | Some(137))
+                && self.start.elapsed() >= self.timeout,
+            ..Default::default()
+        };
+        (Vec::new(), usage)
+    }
+}
+
+/// Runs the shell on the host without any isolation
+///
+/// Both modes run as the user of the bot process, which also owns the files
+/// it creates for them, so the bot does not need to be root. The guest home
+/// and the host home are expected to be the same directory. Invocations with
+/// limits besides the timeout are refused, e.g. after lifting the default
+/// ones with `--limit=non-root.memory-max=infinity`.
+#[derive(Debug)]
+pub struct Local {
+    shell: String,
+    home: PathBuf,
+}
+
+impl Local {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            shell: options.shell.clone(),
+            home: options.user_host_home.clone(),
+        }
+    }
+}
+
+impl Executor for Local {
+    fn launch(&self, invocation: &Invocation) -> Result<Launch, AceError> {
+        match invocation.mode {
+            Mode::NonRoot | Mode::Root => (),
+            mode => return Err(AceError::InvalidMode(mode)),
+        }
+        check_limits(&invocation.limits, false)?;
+        let mut command = Command::new("timeout");
+        command
+            .args(["--kill-after=5", &invocation.timeout.to_string()])
+            .args([&self.shell, "--login"])
+            .current_dir(&self.home);
+        Ok(Launch::Command {
+            command,
+            monitor: Box::new(TimeoutMonitor::new(invocation)),
+        })
+    }
+
+    fn session(&self, invocation: &Invocation) -> Result<Command, AceError> {
+        match invocation.mode {
+            Mode::NonRoot | Mode::Root => (),
+            mode => return Err(AceError::InvalidMode(mode)),
+        }
+        check_limits(&invocation.limits, false)?;
+        let mut command = Command::new(&self.shell);
+        command.arg("--login").current_dir(&self.home);
+        Ok(command)
+    }
+
+    fn cancel(&self, _invocation: &Invocation, pid: Option<u32>) -> Option<Command> {
+        kill(pid)
+    }
+}
+
+/// Runs the shell on the host inside a bubblewrap sandbox
+///
+/// The host root is mounted read-only and the host home is mounted read-write
+/// at the guest home. Root mode only maps uid 0 in a new user namespace.
+/// Invocations with limits besides the timeout and network access are refused.
+#[derive(Debug)]
+pub struct Bubblewrap {
+    shell: String,
+    uid: u32,
+    gid: u32,
+    host_home: PathBuf,
+    guest_home: PathBuf,
+}
+
+impl Bubblewrap {
+    pub fn new(options: &Options, uid: u32, gid: u32) -> Self {
+        Self {
+            shell: options.shell.clone(),
+            uid,
+            gid,
+            host_home: options.user_host_home.clone(),
+            guest_home: options.user_guest_home.clone(),
+        }
+    }
+
+    /// Adds the arguments of `bwrap` after `--die-with-parent`
+    fn sandbox(&self, command: &mut Command, invocation: &Invocation) -> Result<(), AceError> {
+        let (uid, gid) = match invocation.mode {
+            Mode::NonRoot => (self.uid, self.gid),
+            Mode::Root => (0, 0),
+            mode => return Err(AceError::InvalidMode(mode)),
+        };
+        check_limits(&invocation.limits, true)?;
+        command.arg("--unshare-all");
+        if !invocation.limits.offline {
+            command.arg("--share-net");
+        }
+        command
+            .arg("--unshare-user")
+            .args(["--uid", &uid.to_string(), "--gid", &gid.to_string()])
+            .args(["--ro-bind", "/", "/"])
+            .args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+            .arg("--bind")
+            .args([&self.host_home, &self.guest_home])
+            .arg("--chdir")
+            .arg(&self.guest_home)
+            .args(["--", &self.shell, "--login"]);
+        Ok(())
+    }
+}
+
+impl Executor for Bubblewrap {
+    fn launch(&self, invocation: &Invocation) -> Result<Launch, AceError> {
+        let mut command = Command::new("timeout");
+        command
+            .args(["--kill-after=5", &invocation.timeout.to_string()])
+            .args(["bwrap", "--die-with-parent", "--new-session"]);
+        self.sandbox(&mut command, invocation)?;
+        Ok(Launch::Command {
+            command,
+            monitor: Box::new(TimeoutMonitor::new(invocation)),
+        })
+    }
+
+    fn session(&self, invocation: &Invocation) -> Result<Command, AceError> {
+        // no --new-session, the terminal belongs to the session alone
+        let mut command = Command::new("bwrap");
+        command.arg("--die-with-parent");
+        self.sandbox(&mut command, invocation)?;
+        Ok(command)
+    }
+
+    fn cancel(&self, _invocation: &Invocation, pid: Option<u32>) -> Option<Command> {
+        kill(pid)
+    }
+}
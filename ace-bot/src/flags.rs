@@ -0,0 +1,98 @@
+//! Flags leading the text of a command, e.g. `/user --timeout=300 sleep 200`
+
+use crate::AceError;
+
+/// Flags given before the text of a command
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// requested timeout in seconds
+    pub timeout: Option<usize>,
+    /// runs without network access, `--offline`
+    pub offline: bool,
+    /// engine of the mode, `--engine=neato` for `/dot`
+    pub engine: Option<String>,
+}
+
+impl Flags {
+    /// Splits the leading flags off `text`
+    ///
+    /// Parsing stops at the first word which is not a known flag, so commands
+    /// starting with other options are left untouched. A lone `--` ends the
+    /// flags and is removed.
+    pub fn parse(text: &str) -> Result<(Self, &str), AceError> {
+        let mut flags = Self::default();
+        let mut rest = text;
+        loop {
+            let (word, after) = split_word(rest.trim_start());
+            if word == "--" {
+                rest = after;
+                break;
+            }
+            if word == "--offline" {
+                flags.offline = true;
+                rest = after;
+                continue;
+            }
+            if let Some(value) = flag_value(word, "--engine", &mut rest, after) {
+                flags.engine = Some(value.to_string());
+                continue;
+            }
+            let Some(value) = flag_value(word, "--timeout", &mut rest, after) else {
+                break;
+            };
+            flags.timeout = Some(parse_timeout(value)?);
+        }
+        Ok((flags, rest))
+    }
+}
+
+/// Splits the `--zip` flag off the path of `/get`, the path is trimmed
+pub fn parse_get(argument: &str) -> (bool, &str) {
+    match split_word(argument.trim_start()) {
+        ("--zip", path) => (true, path.trim_end()),
+        _ => (false, argument.trim()),
+    }
+}
+
+/// Value of `word` if it is the flag `name`, as `name=VALUE` or `name VALUE`
+///
+/// `rest` is moved past the value, `after` is the text after `word`.
+fn flag_value<'a>(
+    word: &'a str,
+    name: &str,
+    rest: &mut &'a str,
+    after: &'a str,
+) -> Option<&'a str> {
+    match word.strip_prefix(name) {
+        Some("") => {
+            let (value, after) = split_word(after);
+            *rest = after;
+            Some(value)
+        }
+        Some(value) if value.starts_with('=') => {
+            *rest = after;
+            Some(&value[1..])
+        }
+        _ => None,
+    }
+}
+
+/// Splits off the first word, the rest starts at the next word
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn parse_timeout(value: &str) -> Result<usize, AceError> {
+    match value.parse() {
+        Ok(0) => Err(AceError::InvalidFlag(
+            "timeout must be positive".to_string(),
+        )),
+        Ok(seconds) => Ok(seconds),
+        Err(_) => Err(AceError::InvalidFlag(format!(
+            "invalid timeout in seconds: {value}"
+        ))),
+    }
+}
@@ -0,0 +1,148 @@
+//! Sandboxing properties of the transient units of commands
+
+use crate::Mode;
+use crate::resources::parse_bool;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Sandboxing properties of the units of a mode, keyed by systemd property name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hardening {
+    pub properties: BTreeMap<String, Property>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Property {
+    Bool(bool),
+    String(String),
+    Paths(Vec<String>),
+}
+
+impl Hardening {
+    /// Built-in profile of `mode`, `home` is kept writable for the non-root user
+    ///
+    /// Root keeps write access to the system, so that packages can still be
+    /// installed, but may not touch the kernel.
+    pub fn defaults(mode: Mode, home: &Path) -> Self {
+        let mut properties = BTreeMap::new();
+        for name in [
+            "ProtectKernelTunables",
+            "ProtectKernelModules",
+            "ProtectKernelLogs",
+            "ProtectClock",
+            "ProtectHostname",
+        ] {
+            properties.insert(name.to_string(), Property::Bool(true));
+        }
+        if mode == Mode::NonRoot {
+            for name in [
+                "NoNewPrivileges",
+                "PrivateTmp",
+                "PrivateDevices",
+                "ProtectControlGroups",
+                "RestrictSUIDSGID",
+                "RestrictRealtime",
+                "LockPersonality",
+            ] {
+                properties.insert(name.to_string(), Property::Bool(true));
+            }
+            properties.insert(
+                "ProtectSystem".to_string(),
+                Property::String("strict".to_string()),
+            );
+            properties.insert(
+                "ReadWritePaths".to_string(),
+                Property::Paths(vec![home.to_string_lossy().into_owned()]),
+            );
+        }
+        Self { properties }
+    }
+
+    /// Profile of `mode`, the defaults with `overrides` applied in order
+    pub fn of(mode: Mode, home: &Path, overrides: &[HardeningOverride]) -> Self {
+        let mut hardening = Self::defaults(mode, home);
+        for o in overrides.iter().filter(|o| o.mode == mode) {
+            hardening
+                .properties
+                .insert(o.name.clone(), o.property.clone());
+        }
+        hardening
+    }
+
+    /// Whether no property has an effect, e.g. after turning off the defaults
+    pub fn is_empty(&self) -> bool {
+        self.properties.values().all(|property| match property {
+            Property::Bool(value) => !value,
+            Property::String(value) => {
+                matches!(value.as_str(), "no" | "false" | "default" | "all")
+            }
+            Property::Paths(paths) => paths.is_empty(),
+        })
+    }
+
+    /// Arguments of `systemd-run` setting the properties
+    pub fn arguments(&self) -> impl Iterator<Item = String> {
+        self.properties.iter().map(|(name, property)| {
+            let value = match property {
+                Property::Bool(true) => "yes".to_string(),
+                Property::Bool(false) => "no".to_string(),
+                Property::String(value) => value.clone(),
+                Property::Paths(paths) => paths.join(" "),
+            };
+            format!("--property={name}={value}")
+        })
+    }
+}
+
+/// Value of `--hardening`, e.g. `non-root.ProtectHome=read-only`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HardeningOverride {
+    pub mode: Mode,
+    pub name: String,
+    pub property: Property,
+}
+
+impl FromStr for HardeningOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected MODE.PROPERTY=VALUE: {s}"))?;
+        let (mode, name) = key
+            .split_once('.')
+            .ok_or_else(|| format!("expected MODE.PROPERTY: {key}"))?;
+        // units only run in the shell modes, the only ones parsed
+        let mode = mode.parse()?;
+        // the type of the property on the bus has to be known
+        let property = match name {
+            "NoNewPrivileges"
+            | "PrivateTmp"
+            | "PrivateDevices"
+            | "PrivateUsers"
+            | "ProtectKernelTunables"
+            | "ProtectKernelModules"
+            | "ProtectKernelLogs"
+            | "ProtectControlGroups"
+            | "ProtectClock"
+            | "ProtectHostname"
+            | "RestrictSUIDSGID"
+            | "RestrictRealtime"
+            | "LockPersonality"
+            | "MemoryDenyWriteExecute" => Property::Bool(parse_bool(value)?),
+            "ProtectSystem" | "ProtectHome" | "ProtectProc" | "ProcSubset" => {
+                Property::String(value.to_string())
+            }
+            "ReadWritePaths" | "ReadOnlyPaths" | "InaccessiblePaths" => {
+                Property::Paths(value.split_whitespace().map(str::to_string).collect())
+            }
+            _ => return Err(format!("unsupported property: {name}")),
+        };
+        Ok(Self {
+            mode,
+            name: name.to_string(),
+            property,
+        })
+    }
+}
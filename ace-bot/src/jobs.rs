@@ -0,0 +1,161 @@
+use crate::executor::Invocation;
+use crate::scheduler::Owner;
+use crate::systemd::Unit;
+use crate::{AceError, Mode, Request};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+pub type JobId = u64;
+
+/// Length of the command preview shown in job lists
+const PREVIEW_LIMIT: usize = 40;
+
+/// Running jobs, so that they can be listed and cancelled from chat
+#[derive(Debug, Default)]
+pub(crate) struct Jobs {
+    entries: Mutex<HashMap<JobId, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    owner: Owner,
+    mode: Mode,
+    background: bool,
+    command: String,
+    started: Instant,
+    stop: Stop,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// How a running job is stopped
+#[derive(Clone, Debug)]
+pub(crate) enum Stop {
+    /// by the command of [`crate::executor::Executor::cancel`]
+    Executor {
+        invocation: Invocation,
+        pid: Option<u32>,
+    },
+    /// by asking the agent to kill the command
+    Agent(Arc<Notify>),
+    /// by stopping the transient unit
+    Unit(Unit),
+    /// by interrupting the command in the session, the shell keeps running
+    Session(Arc<Notify>),
+}
+
+/// A running job as listed by [`crate::AceBot::jobs`]
+#[derive(Clone, Debug)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub owner: Owner,
+    pub mode: Mode,
+    pub background: bool,
+    /// first line of the command, shortened
+    pub command: String,
+    pub elapsed: Duration,
+}
+
+impl Jobs {
+    pub(crate) fn register(self: &Arc<Self>, id: JobId, request: &Request, stop: Stop) -> JobGuard {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let entry = Entry {
+            owner: request.owner.clone(),
+            mode: request.mode,
+            background: request.background,
+            command: preview(&request.text),
+            started: Instant::now(),
+            stop,
+            cancelled: cancelled.clone(),
+        };
+        self.entries.lock().unwrap().insert(id, entry);
+        JobGuard {
+            id,
+            jobs: self.clone(),
+            cancelled,
+        }
+    }
+
+    /// Lists the jobs of `chat`, or of all chats if it is `None`
+    pub(crate) fn list(&self, chat: Option<&str>) -> Vec<JobInfo> {
+        let entries = self.entries.lock().unwrap();
+        let mut jobs: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| chat.is_none_or(|c| entry.owner.chat == c))
+            .map(|(id, entry)| JobInfo {
+                id: *id,
+                owner: entry.owner.clone(),
+                mode: entry.mode,
+                background: entry.background,
+                command: entry.command.clone(),
+                elapsed: entry.started.elapsed(),
+            })
+            .collect();
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    /// Marks the job as cancelled, returning how to stop it
+    ///
+    /// Only the owner of the job may cancel it unless `privileged` is set.
+    pub(crate) fn cancel(
+        &self,
+        id: JobId,
+        requester: &Owner,
+        privileged: bool,
+    ) -> Result<Stop, AceError> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&id).ok_or(AceError::JobNotFound(id))?;
+        if !privileged && entry.owner.user != requester.user {
+            return Err(AceError::PermissionDenied(id));
+        }
+        entry.cancelled.store(true, Ordering::Relaxed);
+        Ok(entry.stop.clone())
+    }
+}
+
+fn preview(text: &str) -> String {
+    let line = text.trim().lines().next().unwrap_or_default();
+    let mut preview: String = line.chars().take(PREVIEW_LIMIT).collect();
+    if preview.len() < text.trim().len() {
+        preview.push('…');
+    }
+    preview
+}
+
+impl fmt::Display for JobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} · {}", self.id, self.mode)?;
+        if self.background {
+            write!(f, " · background")?;
+        }
+        write!(f, " · {}s · {}", self.elapsed.as_secs(), self.command)
+    }
+}
+
+/// Registration of a running job, removed on drop
+#[derive(Debug)]
+pub(crate) struct JobGuard {
+    id: JobId,
+    jobs: Arc<Jobs>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobGuard {
+    pub(crate) fn id(&self) -> JobId {
+        self.id
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.jobs.entries.lock().unwrap().remove(&self.id);
+    }
+}
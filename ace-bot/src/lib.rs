@@ -1,21 +1,58 @@
+use agent::Agent;
 use clap::Parser;
-use users::{Group, User, get_group_by_name, get_user_by_name};
+use execution::{Artifact, Event, Execution, ExecutionResult};
+use executor::{Executor, ExecutorKind, Invocation, Launch};
+use hardening::HardeningOverride;
+use jobs::{JobId, JobInfo, Jobs, Stop};
+use modes::ModeSpec;
+use resources::{LimitOverride, ResourceLimits};
+use rustix::fs::FileType;
+use scheduler::{Owner, Queued, Scheduler};
+use session::{Session, Sessions};
+use systemd::MachineBuses;
+use tasks::{TaskDir, TaskDirs};
+use users::{get_group_by_name, get_user_by_name};
 
+mod agent;
+mod archive;
+pub mod execution;
+pub mod executor;
+pub mod flags;
+pub mod hardening;
+pub mod jobs;
+pub mod modes;
 pub mod pastebin;
+pub mod resources;
+pub mod scheduler;
+pub mod session;
+pub mod systemd;
+pub mod tasks;
 
-use mktemp::Temp;
-use std::os::unix::fs::chown;
-use std::path::{Path, PathBuf, StripPrefixError};
-use std::process::{Output, Stdio};
-use std::{fmt, io};
-use tokio::fs::{File, OpenOptions, create_dir_all};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
 
 #[derive(Debug)]
 pub struct AceBot {
     options: Options,
-    user_mode_user: User,
-    user_mode_group: Group,
+    modes: Vec<Mode>,
+    tasks: TaskDirs,
+    executor: Box<dyn Executor>,
+    agent: Option<Agent>,
+    machine_buses: MachineBuses,
+    invocation_prefix: String,
+    next_invocation: AtomicU64,
+    scheduler: Arc<Scheduler>,
+    background_scheduler: Arc<Scheduler>,
+    jobs: Arc<Jobs>,
+    sessions: Arc<Sessions>,
 }
 
 #[derive(Clone, Debug, Parser)]
@@ -23,6 +60,15 @@ pub struct AceBot {
 pub struct Options {
     #[arg(short, long, default_value = "60")]
     pub timeout: usize,
+    /// longest timeout in seconds members may request with `--timeout`
+    #[arg(long, default_value = "300")]
+    pub max_timeout: usize,
+    /// longest timeout in seconds managers may request with `--timeout`
+    #[arg(long, default_value = "3600")]
+    pub manager_max_timeout: usize,
+    /// timeout of background jobs in seconds
+    #[arg(long, default_value = "3600")]
+    pub background_timeout: usize,
     #[arg(short, long, default_value = "/bin/sh")]
     pub shell: String,
     #[arg(long)]
@@ -39,15 +85,141 @@ pub struct Options {
     pub reset_indicator: PathBuf,
     #[arg(long)]
     pub machine_unit: String,
+    #[arg(long, value_enum, default_value_t)]
+    pub executor: ExecutorKind,
+    /// socket of ace-bot-agent, commands fall back to the executor while it is unavailable
+    /// and for modes with a hardening profile, which the agent does not enforce
+    #[arg(long)]
+    pub agent_socket: Option<PathBuf>,
+    #[arg(long, default_value = "1048576")]
+    pub output_limit: usize,
+    /// total bytes of artifacts returned from `$ACE_OUT`
+    #[arg(long, default_value = "16777216")]
+    pub artifact_limit: usize,
+    /// gives each chat its own working directory in this directory of the guest home
+    ///
+    /// Commands in non-root mode and sessions start in it, files sent in
+    /// chat are saved to the inbox in it and `/get` takes relative paths
+    /// from it.
+    #[arg(long, value_name = "DIR")]
+    pub chat_workdirs: Option<PathBuf>,
+    /// directory in the working directory of a chat receiving files sent in it
+    #[arg(long, default_value = "inbox")]
+    pub inbox: PathBuf,
+    /// largest file in bytes accepted from chat, into the inbox or as stdin
+    #[arg(long, default_value = "20971520")]
+    pub upload_limit: usize,
+    /// largest file in bytes sent by `/get`, zipped directories included
+    #[arg(long, default_value = "52428800")]
+    pub get_limit: usize,
+    #[arg(long, default_value = "4")]
+    pub max_jobs: usize,
+    #[arg(long, default_value = "2")]
+    pub max_jobs_per_chat: usize,
+    #[arg(long, default_value = "2")]
+    pub max_jobs_per_user: usize,
+    /// background jobs have slots of their own, so that they do not hold up others
+    #[arg(long, default_value = "2")]
+    pub max_background_jobs: usize,
+    #[arg(long, default_value = "1")]
+    pub max_background_jobs_per_user: usize,
+    /// seconds after which an idle session is ended
+    #[arg(long, default_value = "600")]
+    pub session_idle_timeout: u64,
+    /// TOML file declaring modes, replacing built-in modes of the same name
+    ///
+    /// The format is described in `modes.toml` of the `ace-bot` crate.
+    #[arg(long = "modes", value_name = "FILE")]
+    pub mode_files: Vec<PathBuf>,
+    /// overrides a resource limit of a mode, e.g. `typst.memory-max=256M`
+    ///
+    /// Limits are `timeout` in seconds, `memory-max` in bytes with an optional
+    /// K, M, G or T suffix, `cpu-quota` in percent, `tasks-max` and `offline`
+    /// as yes or no. Limits other than `timeout` and `offline` can be removed
+    /// with `infinity`.
+    #[arg(long = "limit", value_name = "MODE.LIMIT=VALUE")]
+    pub limits: Vec<LimitOverride>,
+    /// sets a sandboxing property of the units of a mode, e.g. `non-root.ProtectHome=tmpfs`
+    ///
+    /// Modes are `non-root` and `root`, properties are named like in
+    /// systemd.exec(5). Booleans are given as yes or no, and paths are
+    /// separated by spaces.
+    #[arg(long = "hardening", value_name = "MODE.PROPERTY=VALUE")]
+    pub hardening: Vec<HardeningOverride>,
+}
+
+/// A command sent from chat
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub owner: Owner,
+    pub mode: Mode,
+    pub text: String,
+    /// runs with the background timeout, the frontend reports only the result
+    pub background: bool,
+    /// timeout in seconds requested by `--timeout`, replaces the default one
+    pub timeout: Option<usize>,
+    /// runs without network access even if the mode allows it
+    pub offline: bool,
+    /// engine requested by `--engine`, one of those of the mode
+    pub engine: Option<String>,
+    pub role: Role,
+    /// data read by shell commands from stdin, e.g. the message replied to
+    ///
+    /// Other modes ignore it.
+    pub stdin: Option<Vec<u8>>,
+    /// runs in the inbox of the chat instead of its working directory, e.g.
+    /// the caption of an uploaded file
+    pub in_inbox: bool,
+}
+
+/// Role of the sender of a request, managers write from the manager chat
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Role {
+    #[default]
+    Member,
+    Manager,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Mode {
     NonRoot,
     Root,
-    Nix,
-    Xelatex,
-    Typst,
+    /// a mode of the registry, its build script runs in non-root mode
+    Custom(&'static ModeSpec),
+}
+
+impl Mode {
+    /// Language of the text of commands, for syntax highlighting
+    pub fn language(&self) -> &str {
+        match self {
+            Mode::NonRoot | Mode::Root => "bash",
+            Mode::Custom(spec) => &spec.language,
+        }
+    }
+
+    /// What commands print, `None` if it is unknown like for shell commands
+    pub fn output(&self) -> Option<modes::Output> {
+        match self {
+            Mode::NonRoot | Mode::Root => None,
+            Mode::Custom(spec) => Some(spec.output),
+        }
+    }
+
+    /// What commands leave in `$ACE_OUT`, `None` if it is unknown
+    pub fn artifacts(&self) -> Option<modes::Output> {
+        match self {
+            Mode::NonRoot | Mode::Root => None,
+            Mode::Custom(spec) => Some(spec.artifacts),
+        }
+    }
+
+    /// File name of what commands print
+    pub fn output_name(&self) -> &str {
+        match self {
+            Mode::NonRoot | Mode::Root => "stdout",
+            Mode::Custom(spec) => &spec.output_name,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -56,12 +228,60 @@ pub enum AceError {
     Io(#[from] std::io::Error),
     #[error("invalid mode: {0}")]
     InvalidMode(Mode),
-    #[error("can not strip temporary file path: {0}")]
-    CanNotStripTempFilePath(StripPrefixError),
+    #[error("unknown mode: {0}")]
+    UnknownMode(String),
+    #[error("invalid modes: {0}")]
+    InvalidModes(String),
     #[error("missing user: {0}")]
     MissingUser(String),
     #[error("missing group: {0}")]
     MissingGroup(String),
+    #[error("execution finished without exit status")]
+    MissingExitStatus,
+    #[error("no running job: {0}")]
+    JobNotFound(JobId),
+    #[error("permission denied to cancel job: {0}")]
+    PermissionDenied(JobId),
+    #[error("failed to cancel job: {0}")]
+    CancelFailed(String),
+    #[error("agent error: {0}")]
+    Agent(#[from] ace_bot_agent::protocol::Error),
+    #[error("agent failed: {0}")]
+    AgentFailed(String),
+    #[error("agent disconnected")]
+    AgentDisconnected,
+    #[error("no session in this chat")]
+    SessionNotFound,
+    #[error("failed to set up session: {0}")]
+    SessionSetup(String),
+    #[error("dbus error: {0}")]
+    Dbus(#[from] zbus::Error),
+    #[error("machine not running: {0}")]
+    MachineNotFound(String),
+    #[error("unit {unit} failed: {result}")]
+    UnitFailed { unit: String, result: String },
+    #[error("job of unit {unit} finished with result: {result}")]
+    JobFailed { unit: String, result: String },
+    #[error("lost track of job: {0}")]
+    JobLost(String),
+    #[error("invalid flag: {0}")]
+    InvalidFlag(String),
+    #[error("timeout of {requested}s exceeds the maximum of {max}s")]
+    TimeoutTooLong { requested: usize, max: usize },
+    #[error("invalid file name: {0}")]
+    InvalidFileName(String),
+    #[error("file of {size} bytes exceeds the upload limit of {max} bytes")]
+    UploadTooLarge { size: usize, max: usize },
+    #[error("path outside of the home: {0}")]
+    OutsideHome(String),
+    #[error("{0} is a directory, get it with --zip")]
+    IsDirectory(String),
+    #[error("{0} is neither a regular file nor a directory")]
+    NotRegularFile(String),
+    #[error("file exceeds the limit of {0} bytes")]
+    FileTooLarge(usize),
+    #[error("the executor can not enforce the limit {0}, lift it with --limit")]
+    UnenforcedLimit(&'static str),
 }
 
 impl AceBot {
@@ -70,199 +290,548 @@ impl AceBot {
             .ok_or_else(|| AceError::MissingUser(options.user_mode_user.clone()))?;
         let group = get_group_by_name(&options.user_mode_group)
             .ok_or_else(|| AceError::MissingGroup(options.user_mode_user.clone()))?;
+        let modes = modes::load(&options.mode_files)?;
+        for o in &options.limits {
+            let known = o.mode.parse::<Mode>().is_ok()
+                || modes.iter().any(|mode| mode.to_string() == o.mode);
+            if !known {
+                return Err(AceError::UnknownMode(o.mode.clone()));
+            }
+        }
+        let executor = executor::from_options(&options, user.uid(), group.gid());
+        // local commands run as the bot itself, which may not be root
+        let (owner_uid, owner_gid) = match options.executor {
+            ExecutorKind::Local => (
+                rustix::process::geteuid().as_raw(),
+                rustix::process::getegid().as_raw(),
+            ),
+            _ => (user.uid(), group.gid()),
+        };
+        let tasks = TaskDirs::new(
+            options.user_host_home.clone(),
+            options.user_guest_home.clone(),
+            owner_uid,
+            owner_gid,
+            options.artifact_limit,
+        );
+        let agent = options
+            .agent_socket
+            .clone()
+            .map(|socket| Agent::new(&options, socket));
+        let sessions = Sessions::new(Duration::from_secs(options.session_idle_timeout));
+        let scheduler = Scheduler::new(scheduler::Limits {
+            global: options.max_jobs,
+            per_chat: options.max_jobs_per_chat,
+            per_user: options.max_jobs_per_user,
+        });
+        let background_scheduler = Scheduler::new(scheduler::Limits {
+            global: options.max_background_jobs,
+            per_chat: options.max_background_jobs,
+            per_user: options.max_background_jobs_per_user,
+        });
+        // units of a previous bot process may still be running
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
         Ok(Self {
             options,
-            user_mode_user: user,
-            user_mode_group: group,
+            modes,
+            tasks,
+            executor,
+            agent,
+            machine_buses: Default::default(),
+            invocation_prefix: format!("ace-bot-{started}"),
+            next_invocation: AtomicU64::new(0),
+            scheduler: Arc::new(scheduler),
+            background_scheduler: Arc::new(background_scheduler),
+            jobs: Default::default(),
+            sessions: Arc::new(sessions),
         })
     }
 
-    pub async fn run(&self, mode: Mode, text: &str) -> Result<Output, AceError> {
-        match mode {
-            Mode::NonRoot | Mode::Root => self.run_bash(mode, text).await,
-            Mode::Nix => self.run_nix(text).await,
-            Mode::Xelatex => self.run_xelatex(text).await,
-            Mode::Typst => self.run_typst(text).await,
+    /// Rejects requests which could not be run, before they are queued
+    pub fn validate(&self, request: &Request) -> Result<(), AceError> {
+        if let Some(stdin) = &request.stdin {
+            self.validate_size(stdin.len())?;
         }
+        engine(request)?;
+        self.request_timeout(request).map(drop)
     }
 
-    pub async fn run_bash(&self, mode: Mode, text: &str) -> Result<Output, AceError> {
-        let mut command = tokio::process::Command::new("systemd-run");
-        command.args([
-            &format!("--machine={}", self.options.machine),
-            "--collect",
-            "--quiet",
-            "--wait",
-            "--pipe",
-            "--service-type=oneshot",
-            &format!("--property=TimeoutStartSec={}", self.options.timeout),
-            "--send-sighup",
-        ]);
-        match mode {
-            Mode::NonRoot => {
-                command.args([
-                    // systemd-run accepts user/group names
-                    &format!("--uid={}", self.options.user_mode_user),
-                    &format!("--gid={}", self.options.user_mode_group),
-                ]);
-                command.arg("--working-directory");
-                command.arg(&self.options.user_guest_home);
-            }
-            Mode::Root => {
-                command.args(["--working-directory=/root"]);
-            }
-            _ => return Err(AceError::InvalidMode(mode)),
+    /// Rejects data of `size` bytes sent in chat, uploads and stdin alike
+    pub fn validate_size(&self, size: usize) -> Result<(), AceError> {
+        let max = self.options.upload_limit;
+        if size > max {
+            return Err(AceError::UploadTooLarge { size, max });
         }
-        command
-            .arg("--")
-            .args([&self.options.shell, "--login"])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true);
-        let mut child = command.spawn()?;
-        let mut stdin = child.stdin.take().unwrap();
-        stdin.write_all(text.as_bytes()).await?;
-        stdin.flush().await?;
-        drop(stdin);
-        let output = child.wait_with_output().await?;
-        Ok(output)
+        Ok(())
     }
 
-    pub async fn run_in_temp_dir<Fn, F>(&self, task: Fn) -> Result<Output, AceError>
-    where
-        Fn: FnOnce(Temp, PathBuf) -> F,
-        F: Future<Output = Result<Output, AceError>>,
-    {
-        let host_dir = self.options.user_host_home.join(".ace-bot").join("tasks");
-        let guest_dir = self.options.user_guest_home.join(".ace-bot").join("tasks");
-        create_dir_all(&host_dir).await?;
-        let host_temp = Temp::new_dir_in(&host_dir)?;
-        let guest_temp = guest_dir.join(
-            host_temp
-                .strip_prefix(&host_dir)
-                .map_err(AceError::CanNotStripTempFilePath)?,
-        );
-        self.ensure_owner(self.options.user_host_home.join(".ace-bot"))?;
-        self.ensure_owner(&host_dir)?;
-        self.ensure_owner(&host_temp)?;
-        task(host_temp, guest_temp).await
-    }
-
-    pub async fn run_nix(&self, expr: &str) -> Result<Output, AceError> {
-        self.run_in_temp_dir(async |host_temp, guest_temp| {
-            let (mut file, _host_path, guest_path) = self
-                .create_file(&host_temp, &guest_temp, "expr.nix")
-                .await?;
-            let content = format!("let pkgs = import <nixpkgs> {{ }}; in {expr}");
-            file.write_all(content.as_bytes()).await?; // utf-8
-            file.flush().await?;
-            let eval_command = format!("nix eval --file {}", guest_path.display());
-            self.run_bash(Mode::NonRoot, &eval_command).await
+    /// Rejects uploads which could not be saved, before they are downloaded
+    pub fn validate_upload(&self, name: &str, size: usize) -> Result<(), AceError> {
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) || name.contains('/')
+        {
+            return Err(AceError::InvalidFileName(name.to_string()));
+        }
+        self.validate_size(size)
+    }
+
+    /// Saves a file sent in chat to the inbox, returns its path in the machine
+    ///
+    /// A file of the same name is replaced.
+    pub async fn upload(&self, chat: &str, name: &str, data: &[u8]) -> Result<PathBuf, AceError> {
+        self.validate_upload(name, data.len())?;
+        let inbox = self.chat_dir(chat).join(&self.options.inbox);
+        Ok(self.tasks.save(&inbox, name, data).await?)
+    }
+
+    /// Working directory of `chat` in the machine, created if chats have their own
+    ///
+    /// Anything else than a directory in its place is an error, it is never removed.
+    pub fn workdir(&self, chat: &str) -> Result<PathBuf, AceError> {
+        let dir = self.chat_dir(chat);
+        self.tasks.create_dir_all(&dir)?;
+        Ok(self.options.user_guest_home.join(dir))
+    }
+
+    /// Working directory of `chat` relative to the home, the home itself unless chats have their own
+    fn chat_dir(&self, chat: &str) -> PathBuf {
+        match &self.options.chat_workdirs {
+            Some(workdirs) => workdirs.join(chat_dir_name(chat)),
+            None => PathBuf::new(),
+        }
+    }
+
+    /// Reads the file at `path` in the machine, zipping it if it is a directory and `zip` is set
+    ///
+    /// Relative paths are taken from the working directory of `chat`, nothing
+    /// outside of the home is read and symlinks are not followed. Reading and
+    /// zipping happen on the blocking pool.
+    pub async fn get(&self, chat: &str, path: &str, zip: bool) -> Result<Artifact, AceError> {
+        let relative = self.home_relative(chat, path)?;
+        let fd = self.tasks.open(&relative)?;
+        let name = relative
+            .file_name()
+            .or(self.options.user_guest_home.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "home".to_string());
+        let limit = self.options.get_limit;
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            let too_large = |e: std::io::Error| match e.kind() {
+                std::io::ErrorKind::FileTooLarge => AceError::FileTooLarge(limit),
+                _ => AceError::Io(e),
+            };
+            match tasks::file_type(&fd).map_err(std::io::Error::from)? {
+                FileType::RegularFile => Ok(Artifact {
+                    name,
+                    data: tasks::read_file(fd, limit).map_err(too_large)?,
+                }),
+                FileType::Directory if zip => Ok(Artifact {
+                    name: format!("{name}.zip"),
+                    data: archive::zip_dir(fd, limit).map_err(too_large)?,
+                }),
+                FileType::Directory => Err(AceError::IsDirectory(path)),
+                _ => Err(AceError::NotRegularFile(path)),
+            }
         })
         .await
+        .map_err(std::io::Error::other)?
     }
 
-    pub async fn run_xelatex(&self, expr: &str) -> Result<Output, AceError> {
-        self.run_in_temp_dir(async |host_temp, guest_temp| {
-            let (mut file, _host_path, _guest_path) = self
-                .create_file(&host_temp, &guest_temp, "main.tex")
-                .await?;
-            let content = format!(
-                r#"\documentclass[dvisvgm, border=5mm]{{standalone}}
+    /// `path` in the machine relative to the home
+    fn home_relative(&self, chat: &str, path: &str) -> Result<PathBuf, AceError> {
+        let outside = || AceError::OutsideHome(path.to_string());
+        let mut relative = Path::new(path);
+        let mut result = PathBuf::new();
+        if relative.is_absolute() {
+            relative = relative
+                .strip_prefix(&self.options.user_guest_home)
+                .map_err(|_| outside())?;
+        } else if let Ok(stripped) = relative.strip_prefix("~") {
+            relative = stripped;
+        } else {
+            result = self.chat_dir(chat);
+        }
+        for component in relative.components() {
+            match component {
+                Component::Normal(component) => result.push(component),
+                Component::CurDir => (),
+                _ => return Err(outside()),
+            }
+        }
+        Ok(result)
+    }
 
-\special{{background White}}
+    /// Waits in the job queue, the job may run once the permit is granted
+    ///
+    /// Background jobs wait in a queue of their own.
+    pub fn enqueue(&self, request: &Request) -> Queued {
+        let scheduler = match request.background {
+            true => &self.background_scheduler,
+            false => &self.scheduler,
+        };
+        scheduler.enqueue(request.owner.clone())
+    }
 
-\begin{{document}}
+    pub async fn run(&self, request: &Request) -> Result<ExecutionResult, AceError> {
+        self.spawn(request).await?.result().await
+    }
 
-{expr}
+    pub async fn spawn(&self, request: &Request) -> Result<Execution, AceError> {
+        match request.mode {
+            Mode::NonRoot | Mode::Root => self.run_bash(request).await,
+            Mode::Custom(spec) => self.run_custom(request, spec).await,
+        }
+    }
 
-\end{{document}}
-"#
-            );
-            file.write_all(content.as_bytes()).await?; // utf-8
+    /// Modes of the registry, for the frontends to match their commands
+    pub fn modes(&self) -> &[Mode] {
+        &self.modes
+    }
+
+    /// Runs the text of the request as a shell script
+    ///
+    /// With `stdin` the script is written to the task directory and sourced
+    /// from there, its stdin is the data instead of the script.
+    pub async fn run_bash(&self, request: &Request) -> Result<Execution, AceError> {
+        self.run_in_temp_dir(async |task_dir| {
+            let Some(stdin) = &request.stdin else {
+                return self
+                    .spawn_bash(request, request.mode, &request.text, task_dir)
+                    .await;
+            };
+            let mut file = task_dir.create_file("script")?;
+            file.write_all(request.text.as_bytes()).await?;
+            file.flush().await?;
+            let mut file = task_dir.create_file("stdin")?;
+            file.write_all(stdin).await?;
             file.flush().await?;
-            let eval_command = format!(
-                r#"cd {}
-echo "===== main.tex =====" >&2
-cat main.tex >&2
-echo "===== xelatex --no-pdf main.tex =====" >&2
-xelatex --no-pdf main.tex >&2
-echo "===== dvisvgm --no-fonts --bbox=papersize main.xdv =====" >&2
-dvisvgm --no-fonts --bbox=papersize main.xdv >&2
-cat main.svg
-"#,
-                guest_temp.display()
-            );
-            self.run_bash(Mode::NonRoot, &eval_command).await
+            let script = format!(". {0}/script < {0}/stdin", task_dir.guest_path().display());
+            self.spawn_bash(request, request.mode, &script, task_dir)
+                .await
         })
         .await
     }
 
-    pub async fn run_typst(&self, expr: &str) -> Result<Output, AceError> {
-        self.run_in_temp_dir(async |host_temp, guest_temp| {
-            let (mut file, _host_path, _guest_path) = self
-                .create_file(&host_temp, &guest_temp, "main.typ")
-                .await?;
-            let content = format!(
-                r#"#set page(
-  width: auto,
-  height: auto,
-  margin: 5mm,
-)
-{expr}
-"#
-            );
+    /// Starts an interactive shell for the chat of `owner`, replacing the previous one
+    pub async fn start_session(&self, owner: &Owner) -> Result<(), AceError> {
+        let id = self.next_invocation.fetch_add(1, Ordering::Relaxed);
+        let invocation = Invocation {
+            mode: Mode::NonRoot,
+            timeout: self.timeout(Mode::NonRoot),
+            limits: self.limits(Mode::NonRoot),
+            name: format!("{}-{id}", self.invocation_prefix),
+        };
+        let command = self.executor.session(&invocation)?;
+        let session = Session::spawn(
+            command,
+            &self.workdir(&owner.chat)?,
+            |pid| self.executor.cancel(&invocation, pid),
+            Duration::from_secs(invocation.timeout as u64),
+        )
+        .await?;
+        self.sessions.insert(owner.chat.clone(), session);
+        Ok(())
+    }
+
+    pub fn has_session(&self, chat: &str) -> bool {
+        self.sessions.get(chat).is_some()
+    }
+
+    pub fn end_session(&self, chat: &str) -> Result<(), AceError> {
+        match self.sessions.remove(chat) {
+            true => Ok(()),
+            false => Err(AceError::SessionNotFound),
+        }
+    }
+
+    /// Feeds the request to the session of its chat
+    ///
+    /// The command is a job like any other, cancelling it interrupts the
+    /// command and keeps the shell running.
+    pub fn run_in_session(&self, request: &Request) -> Result<Execution, AceError> {
+        let chat = request.owner.chat.clone();
+        let session = self.sessions.get(&chat).ok_or(AceError::SessionNotFound)?;
+        let timeout = Duration::from_secs(self.request_timeout(request)? as u64);
+        let id = self.next_invocation.fetch_add(1, Ordering::Relaxed);
+        let interrupt = Arc::new(Notify::new());
+        let job = self
+            .jobs
+            .register(id, request, Stop::Session(interrupt.clone()));
+        let (sender, execution) = Execution::channel(id, self.options.output_limit);
+        let sessions = self.sessions.clone();
+        let text = request.text.clone();
+        tokio::spawn(async move {
+            match session.run(&text, timeout, &interrupt, &sender).await {
+                Ok(Some(mut exit)) => {
+                    exit.cancelled = job.is_cancelled();
+                    let _ = sender.send(Ok(Event::Exit(exit))).await;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                }
+            }
+            if session.is_closed().await {
+                sessions.remove_session(&chat, &session);
+            }
+            drop(job);
+        });
+        Ok(execution)
+    }
+
+    /// Resource limits of jobs of `mode`
+    pub fn limits(&self, mode: Mode) -> ResourceLimits {
+        ResourceLimits::of(mode, &self.options.limits)
+    }
+
+    /// Timeout of foreground jobs of `mode` in seconds
+    pub fn timeout(&self, mode: Mode) -> usize {
+        self.limits(mode).timeout.unwrap_or(self.options.timeout)
+    }
+
+    /// Longest timeout `role` may request in seconds
+    pub fn max_timeout(&self, role: Role) -> usize {
+        match role {
+            Role::Member => self.options.max_timeout,
+            Role::Manager => self.options.manager_max_timeout,
+        }
+    }
+
+    /// Timeout of `request` in seconds, the requested one if it is within the maximum
+    fn request_timeout(&self, request: &Request) -> Result<usize, AceError> {
+        match request.timeout {
+            Some(requested) => {
+                let max = self.max_timeout(request.role);
+                if requested > max {
+                    return Err(AceError::TimeoutTooLong { requested, max });
+                }
+                Ok(requested)
+            }
+            None if request.background => Ok(self.options.background_timeout),
+            None => Ok(self.timeout(request.mode)),
+        }
+    }
+
+    /// Lists running jobs of `chat`, or of all chats if it is `None`
+    pub fn jobs(&self, chat: Option<&str>) -> Vec<JobInfo> {
+        self.jobs.list(chat)
+    }
+
+    /// Stops a running job
+    ///
+    /// Only the owner of the job may cancel it unless `privileged` is set.
+    pub async fn cancel(
+        &self,
+        id: JobId,
+        requester: &Owner,
+        privileged: bool,
+    ) -> Result<(), AceError> {
+        let (invocation, pid) = match self.jobs.cancel(id, requester, privileged)? {
+            Stop::Executor { invocation, pid } => (invocation, pid),
+            Stop::Agent(notify) | Stop::Session(notify) => {
+                notify.notify_one();
+                return Ok(());
+            }
+            Stop::Unit(unit) => return unit.stop().await,
+        };
+        let Some(mut command) = self.executor.cancel(&invocation, pid) else {
+            return Err(AceError::CancelFailed("job is not cancellable".to_string()));
+        };
+        let output = command.output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(AceError::CancelFailed(stderr.trim().to_string()));
+        }
+        Ok(())
+    }
+
+    /// Runs the script through the agent if available, or the executor otherwise
+    ///
+    /// Files the script leaves in `$ACE_OUT` of the task directory are
+    /// returned as artifacts.
+    async fn spawn_bash(
+        &self,
+        request: &Request,
+        mode: Mode,
+        script: &str,
+        task_dir: TaskDir,
+    ) -> Result<Execution, AceError> {
+        let id = self.next_invocation.fetch_add(1, Ordering::Relaxed);
+        let mut limits = self.limits(request.mode);
+        limits.offline |= request.offline;
+        let invocation = Invocation {
+            mode,
+            timeout: self.request_timeout(request)?,
+            limits,
+            name: format!("{}-{id}", self.invocation_prefix),
+        };
+        let mut input = format!("export ACE_OUT='{}'\n", task_dir.out_guest_path().display());
+        if mode == Mode::NonRoot || request.in_inbox {
+            let mut workdir = self.workdir(&request.owner.chat)?;
+            if request.in_inbox {
+                workdir.push(&self.options.inbox);
+            }
+            input.push_str(&format!("cd '{}'\n", workdir.display()));
+        }
+        input.push_str(script);
+        let input = input.into_bytes();
+        // the agent stops commands with the connection, background jobs are
+        // detached units instead
+        if !request.background
+            && let Some(agent) = &self.agent
+            && let Some(spawn) = agent.spawn_frame(&invocation)?
+            && let Some(stream) = agent.connect().await
+        {
+            let kill = Arc::new(Notify::new());
+            let job = self.jobs.register(id, request, Stop::Agent(kill.clone()));
+            return Ok(agent::execute(
+                stream,
+                spawn,
+                input,
+                kill,
+                job,
+                task_dir,
+                self.options.output_limit,
+            ));
+        }
+        let (mut command, monitor) = match self.executor.launch(&invocation)? {
+            Launch::Command { command, monitor } => (command, monitor),
+            Launch::Unit(unit) => {
+                let started = systemd::start(&self.machine_buses, &unit).await?;
+                let job = self.jobs.register(id, request, Stop::Unit(started.unit()));
+                return Ok(started.execute(input, job, task_dir, self.options.output_limit));
+            }
+        };
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let child = command.spawn()?;
+        let stop = Stop::Executor {
+            invocation,
+            pid: child.id(),
+        };
+        let job = self.jobs.register(id, request, stop);
+        Ok(Execution::spawn(
+            child,
+            input,
+            monitor,
+            job,
+            task_dir,
+            self.options.output_limit,
+        ))
+    }
+
+    pub async fn run_in_temp_dir<Fn, F, T>(&self, task: Fn) -> Result<T, AceError>
+    where
+        Fn: FnOnce(TaskDir) -> F,
+        F: Future<Output = Result<T, AceError>>,
+    {
+        task(self.tasks.create()?).await
+    }
+
+    /// Writes the wrapped text to the source file of the mode and runs its build script
+    ///
+    /// The compile script of compiled modes runs first, the build script only
+    /// runs if it succeeds.
+    pub async fn run_custom(
+        &self,
+        request: &Request,
+        spec: &ModeSpec,
+    ) -> Result<Execution, AceError> {
+        self.run_in_temp_dir(async |task_dir| {
+            let mut file = task_dir.create_file(&spec.source)?;
+            let content = spec.wrapper.replace("{text}", &request.text);
             file.write_all(content.as_bytes()).await?; // utf-8
             file.flush().await?;
-            let eval_command = format!(
-                r#"cd {}
-echo "===== main.typ =====" >&2
-cat main.typ >&2
-echo "===== typst compile --format=svg main.typ =====" >&2
-typst compile --format=svg main.typ >&2
-cat main.svg
-"#,
-                guest_temp.display()
-            );
-            self.run_bash(Mode::NonRoot, &eval_command).await
+            let dir = task_dir.guest_path();
+            let source = dir.join(&spec.source);
+            let engine = engine(request)?;
+            let expand = |script: &str| {
+                script
+                    .replace("{dir}", &dir.display().to_string())
+                    .replace("{source}", &source.display().to_string())
+                    .replace("{engine}", engine)
+            };
+            let mut script = String::new();
+            if let Some(compile) = &spec.compile {
+                // the output of the compiler is read from the task directory
+                script = format!(
+                    "(\n{}\n) >{} 2>&1 </dev/null || exit\n: >{}\n",
+                    expand(compile),
+                    task_dir.compile_log_guest_path().display(),
+                    task_dir.compile_ok_guest_path().display(),
+                );
+            }
+            script.push_str(&expand(&spec.build));
+            self.spawn_bash(request, Mode::NonRoot, &script, task_dir)
+                .await
         })
         .await
     }
 
-    pub fn ensure_owner<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
-        let p = path.as_ref();
-        chown(
-            p,
-            Some(self.user_mode_user.uid()),
-            Some(self.user_mode_group.gid()),
-        )
+    /// Resets the machine, returning once its unit is restarted
+    pub async fn reset(&self) -> Result<(), AceError> {
+        File::create(&self.options.reset_indicator).await?;
+        self.sessions.clear();
+        self.machine_buses.clear().await;
+        systemd::restart(&self.options.machine_unit).await
     }
+}
 
-    pub async fn create_file(
-        &self,
-        host_temp: &Temp,
-        guest_temp: &Path,
-        name: &str,
-    ) -> Result<(File, PathBuf, PathBuf), io::Error> {
-        let host_path = host_temp.join(name);
-        let guest_path = guest_temp.join(name);
-        let file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(&host_path)
-            .await?;
-        self.ensure_owner(&host_path)?;
-        Ok((file, host_path, guest_path))
-    }
-
-    pub async fn reset(&self) -> Result<Output, AceError> {
-        File::create(&self.options.reset_indicator).await?;
-        let output = tokio::process::Command::new("systemctl")
-            .args(["restart", &self.options.machine_unit])
-            .output()
-            .await?;
-        Ok(output)
+/// Name of the working directory of `chat`
+///
+/// Bytes other than ASCII alphanumerics, `-` and `_` are percent-encoded, so
+/// that distinct chats never share a directory.
+fn chat_dir_name(chat: &str) -> String {
+    chat.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => char::from(b).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Engine of the request, the one given by `--engine` or the default of its mode
+///
+/// Only engines declared by the mode are returned, they are safe in scripts.
+fn engine(request: &Request) -> Result<&str, AceError> {
+    let engines = match request.mode {
+        Mode::Custom(spec) => spec.engines.as_slice(),
+        Mode::NonRoot | Mode::Root => &[],
+    };
+    match &request.engine {
+        None => Ok(engines.first().map_or("", String::as_str)),
+        Some(engine) if engines.contains(engine) => Ok(engine),
+        Some(engine) if engines.is_empty() => Err(AceError::InvalidFlag(format!(
+            "{} mode has no engines: {engine}",
+            request.mode
+        ))),
+        Some(engine) => Err(AceError::InvalidFlag(format!(
+            "unknown engine: {engine}, one of {}",
+            engines.join(", ")
+        ))),
+    }
+}
+
+/// Parses the shell modes, the others are looked up in the registry
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "root" => Ok(Mode::Root),
+            "non-root" => Ok(Mode::NonRoot),
+            _ => Err(format!("unknown mode: {s}")),
+        }
     }
 }
 
@@ -271,9 +840,7 @@ impl fmt::Display for Mode {
         match self {
             Mode::Root => write!(f, "root"),
             Mode::NonRoot => write!(f, "non-root"),
-            Mode::Nix => write!(f, "nix"),
-            Mode::Xelatex => write!(f, "xelatex"),
-            Mode::Typst => write!(f, "typst"),
+            Mode::Custom(spec) => write!(f, "{}", spec.name),
         }
     }
 }
@@ -0,0 +1,196 @@
+//! Registry of the modes rendering or evaluating the text of a command
+//!
+//! Modes are declared in TOML, the built-in ones in `modes.toml` of this
+//! crate. Files given by `--modes` add modes or replace them by name.
+
+use crate::resources::{Limit, ResourceLimits};
+use crate::{AceError, Mode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+const BUILTIN: &str = include_str!("../modes.toml");
+
+/// Commands of the frontends which modes may not take
+pub const RESERVED_COMMANDS: &[&str] = &[
+    "start",
+    "user",
+    "root",
+    "bg",
+    "reset",
+    "cancel",
+    "kill",
+    "jobs",
+    "get",
+    "pwd",
+    "session",
+    "endsession",
+];
+
+/// What the build script of a mode prints or leaves in `$ACE_OUT`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    #[default]
+    Text,
+    Image,
+    Audio,
+    Document,
+}
+
+/// A mode declared in the registry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeSpec {
+    pub name: String,
+    /// chat command, without the leading `/` or `!`
+    pub command: String,
+    /// file in the task directory the wrapped text is written to
+    pub source: String,
+    /// content of the source file, `{text}` is replaced by the text
+    pub wrapper: String,
+    /// shell script compiling the source before the build script runs,
+    /// its output is reported as diagnostics
+    pub compile: Option<String>,
+    /// shell script, `{dir}` and `{source}` are replaced by guest paths
+    pub build: String,
+    pub output: Output,
+    /// file name of output other than text
+    pub output_name: String,
+    /// what the files left in `$ACE_OUT` mostly are, frontends still check
+    /// each file
+    pub artifacts: Output,
+    /// language of the text for syntax highlighting
+    pub language: String,
+    /// engines selected by `--engine` for `{engine}` in the build script,
+    /// the first is the default
+    pub engines: Vec<String>,
+    pub limits: ResourceLimits,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ModeConfig {
+    command: Option<String>,
+    source: String,
+    #[serde(default = "default_wrapper")]
+    wrapper: String,
+    compile: Option<String>,
+    build: String,
+    #[serde(default)]
+    output: Output,
+    output_name: Option<String>,
+    #[serde(default)]
+    artifacts: Output,
+    #[serde(default = "default_language")]
+    language: String,
+    #[serde(default)]
+    engines: Vec<String>,
+    #[serde(default)]
+    limits: BTreeMap<String, toml::Value>,
+}
+
+fn default_wrapper() -> String {
+    "{text}".to_string()
+}
+
+fn default_language() -> String {
+    "text".to_string()
+}
+
+/// Loads the built-in modes and those of `files`, in order
+///
+/// Modes live as long as the process, so that `Mode` can refer to them.
+pub fn load(files: &[PathBuf]) -> Result<Vec<Mode>, AceError> {
+    let mut configs = parse(BUILTIN, "built-in modes")?;
+    for file in files {
+        let text = std::fs::read_to_string(file)?;
+        configs.extend(parse(&text, &file.display().to_string())?);
+    }
+    let mut specs: Vec<&'static ModeSpec> = Vec::new();
+    for (name, config) in configs {
+        let spec = spec(name, config)?;
+        if let Some(other) = specs.iter().find(|other| other.command == spec.command) {
+            return Err(AceError::InvalidModes(format!(
+                "command {} of mode {} is taken by mode {}",
+                spec.command, spec.name, other.name
+            )));
+        }
+        specs.push(Box::leak(Box::new(spec)));
+    }
+    Ok(specs.into_iter().map(Mode::Custom).collect())
+}
+
+impl ModeSpec {
+    /// Regex matching the command after `prefix`, e.g. `/` or `!`, with the
+    /// text in the second group
+    ///
+    /// The command has to end at a space or the end of the message, so that
+    /// `/c` does not take `/clear`. Neither the prefix nor the command, a
+    /// plain word, need escaping.
+    pub fn command_pattern(&self, prefix: &str) -> String {
+        format!(
+            "^{prefix}{}(@[a-zA-Z_]+)?(?:[[:space:]]+|$)(.*)$",
+            self.command
+        )
+    }
+}
+
+fn parse(text: &str, origin: &str) -> Result<BTreeMap<String, ModeConfig>, AceError> {
+    toml::from_str(text).map_err(|e| AceError::InvalidModes(format!("{origin}: {e}")))
+}
+
+fn spec(name: String, config: ModeConfig) -> Result<ModeSpec, AceError> {
+    let invalid = |reason: String| AceError::InvalidModes(format!("mode {name}: {reason}"));
+    if name.parse::<Mode>().is_ok() {
+        return Err(invalid("shell modes can not be replaced".to_string()));
+    }
+    let command = config.command.unwrap_or_else(|| name.clone());
+    if command.is_empty()
+        || !command
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(invalid(format!("invalid command: {command}")));
+    }
+    // the patterns of the reserved commands do not end at a word boundary
+    if let Some(reserved) = RESERVED_COMMANDS.iter().find(|r| command.starts_with(*r)) {
+        return Err(invalid(format!(
+            "command is taken by /{reserved}: {command}"
+        )));
+    }
+    if config.source.is_empty() || config.source.contains('/') || config.source.starts_with('.') {
+        return Err(invalid(format!("invalid source: {}", config.source)));
+    }
+    if let Some(engine) = config.engines.iter().find(|engine| !is_word(engine)) {
+        return Err(invalid(format!("invalid engine: {engine}")));
+    }
+    let mut limits = ResourceLimits::defaults(Mode::NonRoot);
+    for (key, value) in &config.limits {
+        let value = match value {
+            toml::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        limits.apply(Limit::parse(key, &value).map_err(invalid)?);
+    }
+    Ok(ModeSpec {
+        command,
+        source: config.source,
+        wrapper: config.wrapper,
+        compile: config.compile,
+        build: config.build,
+        output: config.output,
+        output_name: config.output_name.unwrap_or_else(|| "stdout".to_string()),
+        artifacts: config.artifacts,
+        language: config.language,
+        engines: config.engines,
+        limits,
+        name,
+    })
+}
+
+/// Engines are plain words, they end up in build scripts
+fn is_word(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
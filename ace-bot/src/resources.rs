@@ -0,0 +1,152 @@
+//! Per-mode resource limits of jobs
+
+use crate::Mode;
+use std::str::FromStr;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Resource limits of a single job
+///
+/// Memory, CPU and task limits are enforced by transient units and by the
+/// agent, the other executors refuse them. Network access is cut by every
+/// executor except the local one, which refuses it as well.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// seconds before the job is stopped, `--timeout` if unset
+    pub timeout: Option<usize>,
+    /// bytes of memory
+    pub memory_max: Option<u64>,
+    /// percentage of one CPU
+    pub cpu_quota: Option<u64>,
+    pub tasks_max: Option<u64>,
+    /// runs without network access
+    pub offline: bool,
+}
+
+impl ResourceLimits {
+    /// Built-in limits of `mode`
+    pub fn defaults(mode: Mode) -> Self {
+        match mode {
+            Mode::NonRoot | Mode::Root => Self {
+                timeout: None,
+                memory_max: Some(512 * MIB),
+                cpu_quota: Some(50),
+                tasks_max: Some(128),
+                offline: false,
+            },
+            Mode::Custom(spec) => spec.limits,
+        }
+    }
+
+    /// Limits of `mode`, the defaults with `overrides` applied in order
+    pub fn of(mode: Mode, overrides: &[LimitOverride]) -> Self {
+        let mut limits = Self::defaults(mode);
+        let name = mode.to_string();
+        for o in overrides.iter().filter(|o| o.mode == name) {
+            limits.apply(o.limit);
+        }
+        limits
+    }
+
+    /// Replaces a single limit
+    pub fn apply(&mut self, limit: Limit) {
+        match limit {
+            Limit::Timeout(timeout) => self.timeout = Some(timeout),
+            Limit::MemoryMax(bytes) => self.memory_max = bytes,
+            Limit::CpuQuota(percent) => self.cpu_quota = percent,
+            Limit::TasksMax(tasks) => self.tasks_max = tasks,
+            Limit::Offline(offline) => self.offline = offline,
+        }
+    }
+}
+
+/// Value of `--limit`, e.g. `typst.memory-max=256M` or `nix.tasks-max=infinity`
+///
+/// The mode is kept by name, modes are only known once they are loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimitOverride {
+    pub mode: String,
+    pub limit: Limit,
+}
+
+/// A single limit, `None` removes the limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Timeout(usize),
+    MemoryMax(Option<u64>),
+    CpuQuota(Option<u64>),
+    TasksMax(Option<u64>),
+    Offline(bool),
+}
+
+impl Limit {
+    /// Parses the limit `name` set to `value`, e.g. `memory-max` and `256M`
+    pub fn parse(name: &str, value: &str) -> Result<Self, String> {
+        Ok(match name {
+            "timeout" => Limit::Timeout(parse_number(value)? as usize),
+            "memory-max" => Limit::MemoryMax(parse_optional(value, parse_bytes)?),
+            "cpu-quota" => Limit::CpuQuota(parse_optional(value, |v| {
+                parse_number(v.strip_suffix('%').unwrap_or(v))
+            })?),
+            "tasks-max" => Limit::TasksMax(parse_optional(value, parse_number)?),
+            "offline" => Limit::Offline(parse_bool(value)?),
+            _ => return Err(format!("unknown limit: {name}")),
+        })
+    }
+}
+
+impl FromStr for LimitOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected MODE.LIMIT=VALUE: {s}"))?;
+        let (mode, name) = key
+            .split_once('.')
+            .ok_or_else(|| format!("expected MODE.LIMIT: {key}"))?;
+        Ok(Self {
+            mode: mode.to_string(),
+            limit: Limit::parse(name, value)?,
+        })
+    }
+}
+
+/// `infinity` removes the limit, like in systemd
+fn parse_optional(
+    value: &str,
+    parse: impl Fn(&str) -> Result<u64, String>,
+) -> Result<Option<u64>, String> {
+    match value {
+        "infinity" => Ok(None),
+        value => parse(value).map(Some),
+    }
+}
+
+pub(crate) fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err(format!("invalid boolean {value}, expected yes or no")),
+    }
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|e| format!("invalid number {value}: {e}"))
+}
+
+/// Parses a size with an optional binary suffix, e.g. `512M`
+fn parse_bytes(value: &str) -> Result<u64, String> {
+    let (number, exponent) = match value.as_bytes().last() {
+        Some(b'K') => (&value[..value.len() - 1], 1),
+        Some(b'M') => (&value[..value.len() - 1], 2),
+        Some(b'G') => (&value[..value.len() - 1], 3),
+        Some(b'T') => (&value[..value.len() - 1], 4),
+        _ => (value, 0),
+    };
+    parse_number(number)?
+        .checked_mul(1024u64.pow(exponent))
+        .ok_or_else(|| format!("size too large: {value}"))
+}
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+
+/// Who a job belongs to, as identified by the frontend
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Owner {
+    pub chat: String,
+    pub user: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub global: usize,
+    pub per_chat: usize,
+    pub per_user: usize,
+}
+
+/// FIFO job queue with global, per-chat and per-user concurrency limits
+///
+/// Waiting jobs are started in order of arrival, skipping the ones whose chat
+/// or user is already at its limit.
+#[derive(Debug)]
+pub struct Scheduler {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_id: u64,
+    running: usize,
+    running_per_chat: HashMap<String, usize>,
+    running_per_user: HashMap<String, usize>,
+    queue: VecDeque<Waiter>,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    owner: Owner,
+    start: oneshot::Sender<()>,
+    position: watch::Sender<usize>,
+}
+
+impl Scheduler {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    pub fn enqueue(self: &Arc<Self>, owner: Owner) -> Queued {
+        let (start, start_receiver) = oneshot::channel();
+        let (position, position_receiver) = watch::channel(0);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back(Waiter {
+            id,
+            owner: owner.clone(),
+            start,
+            position,
+        });
+        self.dispatch(&mut state);
+        Queued {
+            id,
+            owner,
+            scheduler: self.clone(),
+            start: start_receiver,
+            position: position_receiver,
+            done: false,
+        }
+    }
+
+    /// Starts every waiter allowed by the limits, then updates queue positions
+    fn dispatch(&self, state: &mut State) {
+        let mut index = 0;
+        while index < state.queue.len() && state.running < self.limits.global {
+            let owner = &state.queue[index].owner;
+            let chat = state.running_per_chat.get(&owner.chat).copied();
+            let user = state.running_per_user.get(&owner.user).copied();
+            if chat.unwrap_or(0) >= self.limits.per_chat
+                || user.unwrap_or(0) >= self.limits.per_user
+            {
+                index += 1;
+                continue;
+            }
+            let waiter = state.queue.remove(index).unwrap();
+            state.acquire(&waiter.owner);
+            let _ = waiter.position.send(0);
+            // the receiver is only dropped together with its waiter
+            let _ = waiter.start.send(());
+        }
+        for (index, waiter) in state.queue.iter().enumerate() {
+            waiter.position.send_if_modified(|p| {
+                let modified = *p != index + 1;
+                *p = index + 1;
+                modified
+            });
+        }
+    }
+
+    fn release(&self, owner: &Owner) {
+        let mut state = self.state.lock().unwrap();
+        state.release(owner);
+        self.dispatch(&mut state);
+    }
+
+    fn cancel(&self, id: u64, owner: &Owner) {
+        let mut state = self.state.lock().unwrap();
+        match state.queue.iter().position(|w| w.id == id) {
+            Some(index) => {
+                state.queue.remove(index);
+            }
+            // started but never turned into a permit
+            None => state.release(owner),
+        }
+        self.dispatch(&mut state);
+    }
+}
+
+impl State {
+    fn acquire(&mut self, owner: &Owner) {
+        self.running += 1;
+        *self.running_per_chat.entry(owner.chat.clone()).or_default() += 1;
+        *self.running_per_user.entry(owner.user.clone()).or_default() += 1;
+    }
+
+    fn release(&mut self, owner: &Owner) {
+        self.running -= 1;
+        decrease(&mut self.running_per_chat, &owner.chat);
+        decrease(&mut self.running_per_user, &owner.user);
+    }
+}
+
+fn decrease(counts: &mut HashMap<String, usize>, key: &str) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// A job waiting in the queue, dropping it leaves the queue
+#[derive(Debug)]
+pub struct Queued {
+    id: u64,
+    owner: Owner,
+    scheduler: Arc<Scheduler>,
+    start: oneshot::Receiver<()>,
+    position: watch::Receiver<usize>,
+    done: bool,
+}
+
+impl Queued {
+    /// Position in the queue starting from 1, or 0 once the job may start
+    pub fn position(&self) -> watch::Receiver<usize> {
+        self.position.clone()
+    }
+
+    pub async fn wait(mut self) -> Permit {
+        // the sender is only dropped after sending
+        let _ = (&mut self.start).await;
+        self.done = true;
+        Permit {
+            owner: self.owner.clone(),
+            scheduler: self.scheduler.clone(),
+        }
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if !self.done {
+            self.scheduler.cancel(self.id, &self.owner);
+        }
+    }
+}
+
+/// A running slot of the scheduler, released on drop
+#[derive(Debug)]
+pub struct Permit {
+    owner: Owner,
+    scheduler: Arc<Scheduler>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.release(&self.owner);
+    }
+}
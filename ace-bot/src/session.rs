@@ -0,0 +1,395 @@
+use crate::AceError;
+use crate::execution::{Event, EventSender, Execution, Exit, Usage};
+use rustix::fs::{OFlags, open};
+use rustix::pty::{OpenptFlags, grantpt, openpt, ptsname, unlockpt};
+use rustix::rand::{GetRandomFlags, getrandom};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::process::{Child, Command};
+use tokio::sync::Notify;
+
+const CHUNK_SIZE: usize = 8192;
+/// Start of the line printed after each input, followed by the token and `$?`
+const MARKER_START: u8 = 0x1e;
+const INTERRUPT: u8 = 0x03;
+const SETUP_OUTPUT_LIMIT: usize = 4096;
+
+/// Interactive shells owned by chats, expired after being idle
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    idle_timeout: Duration,
+}
+
+impl Sessions {
+    pub(crate) fn new(idle_timeout: Duration) -> Self {
+        Self {
+            sessions: Default::default(),
+            idle_timeout,
+        }
+    }
+
+    pub(crate) fn get(&self, chat: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(chat).cloned()
+    }
+
+    /// Takes over the session, replacing the previous one of the chat
+    pub(crate) fn insert(self: &Arc<Self>, chat: String, session: Session) {
+        let session = Arc::new(session);
+        let watched = Arc::downgrade(&session);
+        self.sessions.lock().unwrap().insert(chat.clone(), session);
+        tokio::spawn(expire(Arc::downgrade(self), chat, watched));
+    }
+
+    /// Removes the session of the chat, the shell is stopped once it is idle
+    pub(crate) fn remove(&self, chat: &str) -> bool {
+        self.sessions.lock().unwrap().remove(chat).is_some()
+    }
+
+    /// Removes `session` if it is still the session of the chat
+    pub(crate) fn remove_session(&self, chat: &str, session: &Arc<Session>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(chat).is_some_and(|s| Arc::ptr_eq(s, session)) {
+            sessions.remove(chat);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+/// Drops the session from `sessions` once it has been idle for too long
+async fn expire(sessions: Weak<Sessions>, chat: String, session: Weak<Session>) {
+    loop {
+        let (Some(sessions), Some(session)) = (sessions.upgrade(), session.upgrade()) else {
+            return;
+        };
+        let idle = session.last_used.lock().unwrap().elapsed();
+        let Some(remaining) = sessions.idle_timeout.checked_sub(idle) else {
+            log::info!("session of chat {chat} expired");
+            sessions.remove_session(&chat, &session);
+            return;
+        };
+        drop((sessions, session));
+        tokio::time::sleep(remaining).await;
+    }
+}
+
+/// A long-lived shell attached to a pseudo terminal
+///
+/// Each input is followed by a `printf` of a marker line carrying a random
+/// token of the input and the exit status, the output up to the marker is the
+/// output of the input. The token is not known before, so the input can not
+/// end early by printing a marker. Echo is turned off on start so the input
+/// itself is not part of the output.
+#[derive(Debug)]
+pub struct Session {
+    pty: tokio::sync::Mutex<Pty>,
+    child: tokio::sync::Mutex<Child>,
+    last_used: Mutex<Instant>,
+    /// stops the shell on drop, killing the executor command is not always enough
+    stop: Option<Command>,
+}
+
+impl Session {
+    /// Spawns `command` on a new pseudo terminal
+    ///
+    /// The shell changes to `workdir` once it is set up.
+    pub(crate) async fn spawn(
+        mut command: Command,
+        workdir: &Path,
+        stop: impl FnOnce(Option<u32>) -> Option<Command>,
+        timeout: Duration,
+    ) -> Result<Self, AceError> {
+        let (master, slave) = open_pty()?;
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .kill_on_drop(true);
+        // SAFETY: only async-signal-safe system calls are made
+        unsafe {
+            command.pre_exec(|| {
+                rustix::process::setsid()?;
+                rustix::process::ioctl_tiocsctty(rustix::stdio::stdin())?;
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        // close the slave side held by the command
+        drop(command);
+        let session = Self {
+            pty: tokio::sync::Mutex::new(Pty::new(master)?),
+            stop: stop(child.id()),
+            child: tokio::sync::Mutex::new(child),
+            last_used: Mutex::new(Instant::now()),
+        };
+        // line editing would print escape sequences around each input
+        let setup = format!(
+            "stty -echo cols 120 rows 40; set +o emacs +o vi 2>/dev/null; \
+            PS1= PS2= PROMPT_COMMAND=; cd '{}'\n",
+            workdir.display()
+        );
+        let (sender, execution) = Execution::channel(0, SETUP_OUTPUT_LIMIT);
+        let run = async {
+            let exit = session.run(&setup, timeout, &Notify::new(), &sender).await;
+            if let Ok(Some(exit)) = exit {
+                let _ = sender.send(Ok(Event::Exit(exit))).await;
+            }
+            drop(sender);
+        };
+        let ((), result) = tokio::join!(run, execution.result());
+        let result = result?;
+        if result.usage.timed_out || !result.status.success() {
+            return Err(AceError::SessionSetup(
+                String::from_utf8_lossy(&result.stdout).into_owned(),
+            ));
+        }
+        Ok(session)
+    }
+
+    /// Feeds `text` to the shell and sends the output until it finishes
+    ///
+    /// The command is interrupted if it does not finish within `timeout`, is
+    /// interrupted through `interrupt` or the receiver is dropped, the shell
+    /// itself keeps running. Returns `None` if the receiver is dropped.
+    pub(crate) async fn run(
+        &self,
+        text: &str,
+        timeout: Duration,
+        interrupt: &Notify,
+        sender: &EventSender,
+    ) -> Result<Option<Exit>, AceError> {
+        let pty = self.pty.lock().await;
+        *self.last_used.lock().unwrap() = Instant::now();
+        let start = Instant::now();
+        let mut input = text.to_string();
+        if !input.ends_with('\n') {
+            input.push('\n');
+        }
+        let token = random_token()?;
+        input.push_str(&format!("printf '\\036%s:%s\\n' {token} \"$?\"\n"));
+        pty.write_all(input.as_bytes()).await?;
+
+        let mut reader = MarkerReader::new(&token);
+        let read = async {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            loop {
+                let n = pty.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok::<_, AceError>(Outcome::Closed);
+                }
+                let (output, status) = reader.push(&buffer[..n]);
+                if !output.is_empty() && sender.send(Ok(Event::Stdout(output))).await.is_err() {
+                    return Ok(Outcome::Dropped);
+                }
+                if let Some(code) = status {
+                    return Ok(Outcome::Finished(code));
+                }
+            }
+        };
+        let outcome = tokio::select! {
+            read = tokio::time::timeout(timeout, read) => read.unwrap_or(Ok(Outcome::TimedOut))?,
+            () = interrupt.notified() => Outcome::Interrupted,
+        };
+        let status = match outcome {
+            Outcome::Finished(code) => ExitStatus::from_raw(code << 8),
+            // the shell exited, e.g. by `exit`
+            Outcome::Closed => self.child.lock().await.wait().await?,
+            Outcome::Dropped | Outcome::TimedOut | Outcome::Interrupted => {
+                pty.write_all(&[INTERRUPT]).await?;
+                ExitStatus::from_raw(0)
+            }
+        };
+        *self.last_used.lock().unwrap() = Instant::now();
+        let rest = reader.finish();
+        if matches!(outcome, Outcome::Dropped)
+            || (!rest.is_empty() && sender.send(Ok(Event::Stdout(rest))).await.is_err())
+        {
+            return Ok(None);
+        }
+        Ok(Some(Exit {
+            status,
+            duration: start.elapsed(),
+            usage: Usage {
+                timed_out: matches!(outcome, Outcome::TimedOut),
+                ..Default::default()
+            },
+            cancelled: false,
+            // commands of sessions have no task directory
+            artifacts: Default::default(),
+            diagnostics: None,
+        }))
+    }
+
+    /// Whether the shell has exited
+    pub(crate) async fn is_closed(&self) -> bool {
+        !matches!(self.child.lock().await.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if !matches!(self.child.get_mut().try_wait(), Ok(None)) {
+            return;
+        }
+        if let Some(mut stop) = self.stop.take()
+            && let Err(e) = stop.stdout(Stdio::null()).stderr(Stdio::null()).spawn()
+        {
+            log::warn!("failed to stop session: {e}");
+        }
+    }
+}
+
+/// How the input of a session stopped
+enum Outcome {
+    /// the marker carrying the exit status was read
+    Finished(i32),
+    /// the shell exited
+    Closed,
+    /// the receiver of the output is gone
+    Dropped,
+    TimedOut,
+    Interrupted,
+}
+
+/// Hex of 16 random bytes
+fn random_token() -> Result<String, io::Error> {
+    let mut bytes = [0; 16];
+    let mut filled = 0;
+    while filled < bytes.len() {
+        filled += getrandom(&mut bytes[filled..], GetRandomFlags::empty())?;
+    }
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn open_pty() -> Result<(OwnedFd, OwnedFd), io::Error> {
+    let master = openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY | OpenptFlags::CLOEXEC)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    let name = ptsname(&master, Vec::new())?;
+    let flags = OFlags::RDWR | OFlags::NOCTTY | OFlags::CLOEXEC;
+    let slave = open(name.as_c_str(), flags, rustix::fs::Mode::empty())?;
+    Ok((master, slave))
+}
+
+/// Non-blocking master side of a pseudo terminal
+#[derive(Debug)]
+struct Pty {
+    master: AsyncFd<OwnedFd>,
+}
+
+impl Pty {
+    fn new(master: OwnedFd) -> Result<Self, io::Error> {
+        rustix::io::ioctl_fionbio(&master, true)?;
+        Ok(Self {
+            master: AsyncFd::new(master)?,
+        })
+    }
+
+    /// Reads from the terminal, returns 0 once the other side is closed
+    async fn read(&self, buffer: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            let mut guard = self.master.readable().await?;
+            match guard.try_io(|fd| rustix::io::read(fd, &mut *buffer).map_err(io::Error::from)) {
+                Ok(Ok(n)) => return Ok(n),
+                // EIO is returned after all slave fds are closed
+                Ok(Err(e)) if e.raw_os_error() == Some(rustix::io::Errno::IO.raw_os_error()) => {
+                    return Ok(0);
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn write_all(&self, mut data: &[u8]) -> Result<(), io::Error> {
+        while !data.is_empty() {
+            let mut guard = self.master.writable().await?;
+            match guard.try_io(|fd| rustix::io::write(fd, data).map_err(io::Error::from)) {
+                Ok(n) => data = &data[n?..],
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits terminal output at the marker line, translating `\r\n` to `\n`
+struct MarkerReader {
+    marker: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl MarkerReader {
+    fn new(token: &str) -> Self {
+        let mut marker = vec![MARKER_START];
+        marker.extend_from_slice(token.as_bytes());
+        marker.push(b':');
+        Self {
+            marker,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the output before the marker, and the status once the marker is complete
+    fn push(&mut self, data: &[u8]) -> (Vec<u8>, Option<i32>) {
+        self.pending.extend_from_slice(data);
+        if let Some(start) = find(&self.pending, &self.marker) {
+            let rest = &self.pending[start + self.marker.len()..];
+            let Some(end) = rest.iter().position(|b| *b == b'\n') else {
+                let output = translate(&self.pending[..start]);
+                self.pending.drain(..start);
+                return (output, None);
+            };
+            let status = String::from_utf8_lossy(&rest[..end]);
+            let code = status.trim().parse().unwrap_or(-1);
+            let output = translate(&self.pending[..start]);
+            self.pending.clear();
+            return (output, Some(code));
+        }
+        // keep what may turn into the marker or a line break
+        let keep = (1..=self.marker.len().min(self.pending.len()))
+            .rev()
+            .find(|n| {
+                self.marker
+                    .starts_with(&self.pending[self.pending.len() - n..])
+            })
+            .or(self.pending.ends_with(b"\r").then_some(1))
+            .unwrap_or(0);
+        let split = self.pending.len() - keep;
+        let output = translate(&self.pending[..split]);
+        self.pending.drain(..split);
+        (output, None)
+    }
+
+    /// Returns the output held back
+    fn finish(self) -> Vec<u8> {
+        translate(&self.pending)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn translate(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    for (i, b) in data.iter().enumerate() {
+        if *b == b'\r' && data.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        output.push(*b);
+    }
+    output
+}
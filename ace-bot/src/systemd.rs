@@ -0,0 +1,472 @@
+//! Transient units and machine restarts through the D-Bus API of systemd
+
+use crate::AceError;
+use crate::execution::{self, Event, EventSender, Execution, Exit, Usage};
+use crate::hardening::{Hardening, Property};
+use crate::jobs::JobGuard;
+use crate::resources::ResourceLimits;
+use crate::tasks::TaskDir;
+use futures::StreamExt;
+use rustix::pipe::{PipeFlags, pipe_with};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use tokio::net::unix::pipe;
+use zbus::address::Transport;
+use zbus::address::transport::Unixexec;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::{Fd, OwnedObjectPath, Value};
+use zbus::{Address, Connection, proxy};
+
+/// `si_code` of a main process which exited
+const CLD_EXITED: i32 = 1;
+/// `si_code` of a main process killed by a signal
+const CLD_KILLED: i32 = 2;
+/// `si_code` of a main process killed by a signal and dumped
+const CLD_DUMPED: i32 = 3;
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait Manager {
+    fn start_transient_unit(
+        &self,
+        name: &str,
+        mode: &str,
+        properties: &[(&str, Value<'_>)],
+        aux: &[(&str, &[(&str, Value<'_>)])],
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+
+    fn get_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn job_removed(
+        &self,
+        id: u32,
+        job: OwnedObjectPath,
+        unit: String,
+        result: String,
+    ) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait UnitObject {
+    fn unref(&self) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Service",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait Service {
+    #[zbus(property)]
+    fn result(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn exec_main_code(&self) -> zbus::Result<i32>;
+
+    #[zbus(property)]
+    fn exec_main_status(&self) -> zbus::Result<i32>;
+
+    #[zbus(property, name = "CPUUsageNSec")]
+    fn cpu_usage_nsec(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn memory_peak(&self) -> zbus::Result<u64>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.machine1.Manager",
+    default_service = "org.freedesktop.machine1",
+    default_path = "/org/freedesktop/machine1"
+)]
+trait MachineManager {
+    fn get_machine(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+}
+
+/// A oneshot service running in a machine with its stdio connected to the bot
+#[derive(Clone, Debug)]
+pub struct TransientUnit {
+    pub machine: String,
+    /// unit name including the `.service` suffix
+    pub name: String,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub working_directory: PathBuf,
+    pub argv: Vec<String>,
+    pub timeout: Duration,
+    pub limits: ResourceLimits,
+    pub hardening: Hardening,
+}
+
+/// A started unit, stopped through the connection that started it
+#[derive(Clone, Debug)]
+pub(crate) struct Unit {
+    manager: ManagerProxy<'static>,
+    name: String,
+}
+
+impl Unit {
+    pub(crate) async fn stop(&self) -> Result<(), AceError> {
+        self.manager.stop_unit(&self.name, "replace").await?;
+        Ok(())
+    }
+}
+
+/// A unit whose start job has been enqueued
+pub(crate) struct Started {
+    unit: Unit,
+    job: OwnedObjectPath,
+    job_removed: JobRemovedStream,
+    service: ServiceProxy<'static>,
+    reference: UnitObjectProxy<'static>,
+    stdin: pipe::Sender,
+    stdout: pipe::Receiver,
+    stderr: pipe::Receiver,
+}
+
+/// `IPAddressDeny=any`, both address families with a zero prefix length
+fn deny_any() -> Vec<(i32, Vec<u8>, u32)> {
+    vec![
+        (libc::AF_INET, vec![0; 4], 0),
+        (libc::AF_INET6, vec![0; 16], 0),
+    ]
+}
+
+/// Starts `unit` in its machine, connecting to it through `buses`
+pub(crate) async fn start(buses: &MachineBuses, unit: &TransientUnit) -> Result<Started, AceError> {
+    let connection = buses.connection(&unit.machine).await?;
+    let started = start_on(&connection, unit).await;
+    if started.is_err() {
+        buses.forget(&unit.machine).await;
+    }
+    started
+}
+
+async fn start_on(connection: &Connection, unit: &TransientUnit) -> Result<Started, AceError> {
+    let manager = ManagerProxy::new(connection).await?;
+    // subscribe before the job can finish
+    let job_removed = manager.receive_job_removed().await?;
+    let (stdin, stdin_writer) = pipe()?;
+    let (stdout_reader, stdout) = pipe()?;
+    let (stderr_reader, stderr) = pipe()?;
+    let timeout = unit.timeout.as_micros() as u64;
+    let exec_start = vec![(unit.argv[0].clone(), unit.argv.clone(), false)];
+    let mut properties = vec![
+        ("Type", Value::from("oneshot")),
+        ("ExecStart", Value::from(exec_start)),
+        (
+            "WorkingDirectory",
+            Value::from(unit.working_directory.to_string_lossy().into_owned()),
+        ),
+        ("TimeoutStartUSec", Value::from(timeout)),
+        ("SendSIGHUP", Value::from(true)),
+        ("CPUAccounting", Value::from(true)),
+        ("MemoryAccounting", Value::from(true)),
+        ("StandardInputFileDescriptor", Value::from(Fd::from(&stdin))),
+        (
+            "StandardOutputFileDescriptor",
+            Value::from(Fd::from(&stdout)),
+        ),
+        (
+            "StandardErrorFileDescriptor",
+            Value::from(Fd::from(&stderr)),
+        ),
+        ("CollectMode", Value::from("inactive-or-failed")),
+        // keeps the unit around until it is unreferenced or the connection is
+        // closed, so that its result can be read after it exits, needs
+        // systemd 254 in the machine
+        ("AddRef", Value::from(true)),
+    ];
+    if let Some(bytes) = unit.limits.memory_max {
+        properties.push(("MemoryMax", Value::from(bytes)));
+    }
+    if let Some(percent) = unit.limits.cpu_quota {
+        properties.push(("CPUQuotaPerSecUSec", Value::from(percent * 10_000)));
+    }
+    if let Some(tasks) = unit.limits.tasks_max {
+        properties.push(("TasksMax", Value::from(tasks)));
+    }
+    if unit.limits.offline {
+        properties.push(("PrivateNetwork", Value::from(true)));
+        properties.push(("IPAddressDeny", Value::from(deny_any())));
+    }
+    for (name, property) in &unit.hardening.properties {
+        let value = match property {
+            Property::Bool(value) => Value::from(*value),
+            Property::String(value) => Value::from(value.as_str()),
+            Property::Paths(paths) => Value::from(paths.clone()),
+        };
+        properties.push((name.as_str(), value));
+    }
+    if let Some(user) = &unit.user {
+        properties.push(("User", Value::from(user.as_str())));
+    }
+    if let Some(group) = &unit.group {
+        properties.push(("Group", Value::from(group.as_str())));
+    }
+    let job = manager
+        .start_transient_unit(&unit.name, "fail", &properties, &[])
+        .await?;
+    // the unit holds the other ends now, output ends once it exits
+    drop(properties);
+    drop((stdin, stdout, stderr));
+    let path = manager.get_unit(&unit.name).await?;
+    let service = ServiceProxy::builder(connection)
+        .path(path.clone())?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let reference = UnitObjectProxy::builder(connection)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    Ok(Started {
+        unit: Unit {
+            manager,
+            name: unit.name.clone(),
+        },
+        job,
+        job_removed,
+        service,
+        reference,
+        stdin: pipe::Sender::from_owned_fd(stdin_writer)?,
+        stdout: pipe::Receiver::from_owned_fd(stdout_reader)?,
+        stderr: pipe::Receiver::from_owned_fd(stderr_reader)?,
+    })
+}
+
+impl Started {
+    pub(crate) fn unit(&self) -> Unit {
+        self.unit.clone()
+    }
+
+    /// Feeds `input` to the unit and forwards its output
+    ///
+    /// `job` and `task_dir` are kept alive until the unit exits.
+    pub(crate) fn execute(
+        self,
+        input: Vec<u8>,
+        job: JobGuard,
+        task_dir: TaskDir,
+        output_limit: usize,
+    ) -> Execution {
+        let (sender, execution) = Execution::channel(job.id(), output_limit);
+        let start = Instant::now();
+        tokio::spawn(async move {
+            match self.forward(input, start, &sender).await {
+                Ok(Some(mut exit)) => {
+                    exit.cancelled = job.is_cancelled();
+                    exit.artifacts = task_dir.artifacts();
+                    exit.diagnostics = task_dir.diagnostics();
+                    let _ = sender.send(Ok(Event::Exit(exit))).await;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                }
+            }
+            drop(job);
+            drop(task_dir);
+        });
+        execution
+    }
+
+    /// Forwards the output of the unit, returns `None` if the execution is dropped
+    ///
+    /// The unit is unreferenced afterwards, so that it is collected once it
+    /// is inactive.
+    async fn forward(
+        self,
+        input: Vec<u8>,
+        start: Instant,
+        sender: &EventSender,
+    ) -> Result<Option<Exit>, AceError> {
+        let reference = self.reference.clone();
+        let result = self.forward_output(input, start, sender).await;
+        if let Err(e) = reference.unref().await {
+            log::warn!("failed to unreference unit: {e}");
+        }
+        result
+    }
+
+    async fn forward_output(
+        mut self,
+        input: Vec<u8>,
+        start: Instant,
+        sender: &EventSender,
+    ) -> Result<Option<Exit>, AceError> {
+        let forwarded = execution::forward_output(
+            self.stdin,
+            self.stdout,
+            self.stderr,
+            input,
+            sender,
+            |data| data,
+        )
+        .await?;
+        if !forwarded {
+            if let Err(e) = self.unit.stop().await {
+                log::warn!("failed to stop unit {}: {e}", self.unit.name);
+            }
+            return Ok(None);
+        }
+        match wait_job(&mut self.job_removed, &self.job).await?.as_str() {
+            // the start job of a oneshot unit fails with its command, and is
+            // canceled once the unit is stopped, both after the command ran
+            "done" | "failed" | "canceled" => (),
+            result => {
+                return Err(AceError::JobFailed {
+                    unit: self.unit.name,
+                    result: result.to_string(),
+                });
+            }
+        }
+        let duration = start.elapsed();
+        let result = self.service.result().await?;
+        let code = self.service.exec_main_code().await?;
+        let status = self.service.exec_main_status().await?;
+        let status = match code {
+            CLD_EXITED => ExitStatus::from_raw((status & 0xff) << 8),
+            CLD_KILLED => ExitStatus::from_raw(status & 0x7f),
+            CLD_DUMPED => ExitStatus::from_raw(status & 0x7f | 0x80),
+            // the main process never ran
+            _ => {
+                return Err(AceError::UnitFailed {
+                    unit: self.unit.name,
+                    result,
+                });
+            }
+        };
+        // both are `u64::MAX` if unknown, `MemoryPeak` needs systemd 256
+        let cpu_time = self.service.cpu_usage_nsec().await.ok();
+        let memory_peak = self.service.memory_peak().await.ok();
+        Ok(Some(Exit {
+            status,
+            duration,
+            usage: Usage {
+                unit: Some(self.unit.name),
+                cpu_time: cpu_time
+                    .filter(|t| *t != u64::MAX)
+                    .map(Duration::from_nanos),
+                memory_peak: memory_peak.filter(|m| *m != u64::MAX),
+                timed_out: result == "timeout",
+                oom_killed: result == "oom-kill",
+            },
+            cancelled: false,
+            artifacts: Default::default(),
+            diagnostics: None,
+        }))
+    }
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), io::Error> {
+    Ok(pipe_with(PipeFlags::CLOEXEC)?)
+}
+
+/// Restarts `unit` on the host and waits for the restart to finish
+pub(crate) async fn restart(unit: &str) -> Result<(), AceError> {
+    let connection = Connection::system().await?;
+    let manager = ManagerProxy::new(&connection).await?;
+    let mut job_removed = manager.receive_job_removed().await?;
+    let job = manager.restart_unit(unit, "replace").await?;
+    match wait_job(&mut job_removed, &job).await?.as_str() {
+        "done" => Ok(()),
+        result => Err(AceError::JobFailed {
+            unit: unit.to_string(),
+            result: result.to_string(),
+        }),
+    }
+}
+
+/// Waits for `job` to be removed, returns its result
+///
+/// systemd sends `JobRemoved` to the client which enqueued the job even if it
+/// is not subscribed.
+async fn wait_job(
+    job_removed: &mut JobRemovedStream,
+    job: &OwnedObjectPath,
+) -> Result<String, AceError> {
+    while let Some(signal) = job_removed.next().await {
+        let args = signal.args()?;
+        if args.job == *job {
+            return Ok(args.result);
+        }
+    }
+    Err(AceError::JobLost(job.to_string()))
+}
+
+/// Connections to the system buses of machines, made once and kept
+///
+/// A connection is dropped once a unit fails to start through it, e.g. after
+/// the machine restarted, and made again for the next unit.
+#[derive(Debug, Default)]
+pub(crate) struct MachineBuses {
+    connections: tokio::sync::Mutex<HashMap<String, Connection>>,
+}
+
+impl MachineBuses {
+    async fn connection(&self, machine: &str) -> Result<Connection, AceError> {
+        let mut connections = self.connections.lock().await;
+        if let Some(connection) = connections.get(machine) {
+            return Ok(connection.clone());
+        }
+        let connection = connect_machine(machine).await?;
+        connections.insert(machine.to_string(), connection.clone());
+        Ok(connection)
+    }
+
+    async fn forget(&self, machine: &str) {
+        self.connections.lock().await.remove(machine);
+    }
+
+    /// Drops all connections, e.g. before the machines restart
+    pub(crate) async fn clear(&self) {
+        self.connections.lock().await.clear();
+    }
+}
+
+/// Connects to the system bus inside `machine`
+///
+/// The bus of the machine only trusts its own root, so the connection goes
+/// through `systemd-stdio-bridge`, which enters the machine in a process of
+/// its own.
+async fn connect_machine(machine: &str) -> Result<Connection, AceError> {
+    let host = Connection::system().await?;
+    MachineManagerProxy::new(&host)
+        .await?
+        .get_machine(machine)
+        .await
+        .map_err(|e| match e {
+            zbus::Error::MethodError(name, _, _)
+                if name.as_str() == "org.freedesktop.machine1.NoSuchMachine" =>
+            {
+                AceError::MachineNotFound(machine.to_string())
+            }
+            e => e.into(),
+        })?;
+    let bridge = Unixexec::new(
+        "systemd-stdio-bridge".into(),
+        None,
+        vec![format!("--machine={machine}").into()],
+    );
+    Ok(
+        zbus::connection::Builder::address(Address::from(Transport::Unixexec(bridge)))?
+            .build()
+            .await?,
+    )
+}
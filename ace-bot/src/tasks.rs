@@ -0,0 +1,490 @@
+//! Task directories and file transfers in the home of the guest user
+//!
+//! The home is writable by the guest, so nothing below it is resolved by path.
+//! Every component is opened relative to its parent with `O_NOFOLLOW` and
+//! ownership is only changed through file descriptors, a component swapped
+//! for a symlink can not redirect writes of the bot.
+
+use crate::execution::{Artifact, Artifacts, Diagnostics};
+use rustix::fs::{
+    AtFlags, Dir, FileType, Gid, Mode, OFlags, Uid, fchown, fstat, mkdirat, openat, renameat,
+    unlinkat,
+};
+use rustix::io::Errno;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+const ACE_BOT_DIR: &str = ".ace-bot";
+const TASKS_DIR: &str = "tasks";
+/// Directory of artifacts in a task directory, `$ACE_OUT` of the command
+const OUT_DIR: &str = "out";
+/// Telegram sends at most 10 files in a media group
+const MAX_ARTIFACTS: usize = 10;
+/// Output of the compiler of compiled modes in a task directory
+const COMPILE_LOG: &str = "compile.log";
+/// Created in a task directory once the compiler succeeded
+const COMPILE_OK: &str = "compile.ok";
+/// Bytes of compiler output kept
+const DIAGNOSTICS_LIMIT: usize = 64 * 1024;
+
+/// Creates task directories under `.ace-bot/tasks` of the home
+#[derive(Debug)]
+pub struct TaskDirs {
+    host_home: PathBuf,
+    guest_home: PathBuf,
+    uid: Uid,
+    gid: Gid,
+    artifact_limit: usize,
+    next: AtomicU64,
+}
+
+impl TaskDirs {
+    /// Task directories are owned by `uid` and `gid`, the guest user
+    ///
+    /// At most `artifact_limit` bytes of artifacts are read from each of them.
+    pub fn new(
+        host_home: PathBuf,
+        guest_home: PathBuf,
+        uid: u32,
+        gid: u32,
+        artifact_limit: usize,
+    ) -> Self {
+        Self {
+            host_home,
+            guest_home,
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(gid),
+            artifact_limit,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates an empty task directory, removed once the `TaskDir` is dropped
+    pub fn create(&self) -> Result<TaskDir, io::Error> {
+        let home = self.open_home()?;
+        let ace_bot = self.open_dir(&home, ACE_BOT_DIR)?;
+        let tasks = self.open_dir(&ace_bot, TASKS_DIR)?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = loop {
+            let id = self.next.fetch_add(1, Ordering::Relaxed);
+            let name = format!("{started:x}-{id}");
+            // the guest may have taken the name already
+            match mkdirat(&tasks, &name, Mode::from_bits_truncate(0o700)) {
+                Err(Errno::EXIST) => continue,
+                result => break result.map(|()| name)?,
+            }
+        };
+        let dir = open_dir_at(&tasks, &name)?;
+        fchown(&dir, Some(self.uid), Some(self.gid))?;
+        let out = self.open_dir(&dir, OUT_DIR)?;
+        let guest_path = self
+            .guest_home
+            .join(ACE_BOT_DIR)
+            .join(TASKS_DIR)
+            .join(&name);
+        Ok(TaskDir {
+            tasks,
+            name,
+            dir,
+            out,
+            guest_path,
+            uid: self.uid,
+            gid: self.gid,
+            artifact_limit: self.artifact_limit,
+        })
+    }
+
+    /// Writes `data` to the file `name` in `dir` of the home, returns its path in the machine
+    ///
+    /// Missing directories are created for the guest user. The data is written
+    /// to a temporary file renamed over `name`, so whatever was there before,
+    /// a symlink included, is replaced rather than written through.
+    pub async fn save(&self, dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf, io::Error> {
+        let parent = self.open_dirs(dir)?;
+        let (temporary, fd) = loop {
+            let id = self.next.fetch_add(1, Ordering::Relaxed);
+            let temporary = format!(".{name}.{id}.upload");
+            match openat(
+                &parent,
+                &temporary,
+                OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+                Mode::from_bits_truncate(0o644),
+            ) {
+                Err(Errno::EXIST) => continue,
+                result => break (temporary, result?),
+            }
+        };
+        let result: Result<(), io::Error> = async {
+            fchown(&fd, Some(self.uid), Some(self.gid))?;
+            let mut file = File::from_std(fd.into());
+            file.write_all(data).await?;
+            file.sync_all().await?;
+            renameat(&parent, &temporary, &parent, name)?;
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            let _ = unlinkat(&parent, &temporary, AtFlags::empty());
+        }
+        result.map(|()| self.guest_home.join(dir).join(name))
+    }
+
+    /// Creates the directory `path` of the home and its parents for the guest user
+    pub fn create_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        self.open_dirs(path).map(drop)
+    }
+
+    /// Opens the directory `path` of the home, creating missing components for the guest user
+    ///
+    /// The path belongs to the user, unlike in `open_dir` nothing in the way
+    /// is removed: a component which is not a directory, a symlink included,
+    /// is an error.
+    fn open_dirs(&self, path: &Path) -> Result<OwnedFd, io::Error> {
+        let mut dir = self.open_home()?;
+        let mut walked = PathBuf::new();
+        for component in path.components() {
+            let Component::Normal(component) = component else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("not a relative path: {}", path.display()),
+                ));
+            };
+            walked.push(component);
+            let created = match mkdirat(&dir, component, Mode::from_bits_truncate(0o755)) {
+                Ok(()) => true,
+                Err(Errno::EXIST) => false,
+                Err(e) => return Err(e.into()),
+            };
+            dir = match open_dir_at(&dir, component) {
+                Err(Errno::LOOP | Errno::NOTDIR) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("not a directory: {}", walked.display()),
+                    ));
+                }
+                result => result?,
+            };
+            if created {
+                fchown(&dir, Some(self.uid), Some(self.gid))?;
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Opens `path` relative to the home, symlinks are not followed in any component
+    pub fn open(&self, path: &Path) -> Result<OwnedFd, io::Error> {
+        let mut fd = self.open_home()?;
+        for component in path.components() {
+            let Component::Normal(component) = component else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("not a relative path: {}", path.display()),
+                ));
+            };
+            fd = open_nofollow(&fd, component)?;
+        }
+        Ok(fd)
+    }
+
+    fn open_home(&self) -> Result<OwnedFd, io::Error> {
+        Ok(rustix::fs::open(
+            &self.host_home,
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?)
+    }
+
+    /// Opens the directory `name` in `parent`, creating it for the guest user
+    ///
+    /// Anything else than a directory in its place, like a symlink, is removed,
+    /// so this is only for directories of the bot in `.ace-bot`.
+    fn open_dir<P: rustix::path::Arg + Copy>(
+        &self,
+        parent: &OwnedFd,
+        name: P,
+    ) -> Result<OwnedFd, io::Error> {
+        let dir = match create_dir_at(parent, name).and_then(|()| open_dir_at(parent, name)) {
+            Err(Errno::LOOP | Errno::NOTDIR) => {
+                unlinkat(parent, name, AtFlags::empty())?;
+                create_dir_at(parent, name)?;
+                open_dir_at(parent, name)?
+            }
+            result => result?,
+        };
+        fchown(&dir, Some(self.uid), Some(self.gid))?;
+        Ok(dir)
+    }
+}
+
+fn create_dir_at<P: rustix::path::Arg>(parent: &OwnedFd, name: P) -> Result<(), Errno> {
+    match mkdirat(parent, name, Mode::from_bits_truncate(0o755)) {
+        Err(Errno::EXIST) => Ok(()),
+        result => result,
+    }
+}
+
+fn open_dir_at<P: rustix::path::Arg>(parent: impl AsFd, name: P) -> Result<OwnedFd, Errno> {
+    openat(
+        parent,
+        name,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+}
+
+/// A task directory, removed with its content on drop
+#[derive(Debug)]
+pub struct TaskDir {
+    tasks: OwnedFd,
+    name: String,
+    dir: OwnedFd,
+    out: OwnedFd,
+    guest_path: PathBuf,
+    uid: Uid,
+    gid: Gid,
+    artifact_limit: usize,
+}
+
+impl TaskDir {
+    /// Path of the directory inside the machine
+    pub fn guest_path(&self) -> &Path {
+        &self.guest_path
+    }
+
+    /// Path of the artifact directory inside the machine
+    pub fn out_guest_path(&self) -> PathBuf {
+        self.guest_path.join(OUT_DIR)
+    }
+
+    /// Reads the regular files in the artifact directory
+    ///
+    /// Files beyond the count or the size limit are counted as omitted.
+    pub fn artifacts(&self) -> Artifacts {
+        let mut artifacts = Artifacts::default();
+        let mut names = match list_dir(&self.out) {
+            Ok(names) => names,
+            Err(e) => {
+                log::warn!("failed to list artifacts of {}: {e}", self.name);
+                return artifacts;
+            }
+        };
+        names.sort();
+        let mut remaining = self.artifact_limit;
+        for name in names {
+            if artifacts.files.len() == MAX_ARTIFACTS {
+                artifacts.omitted += 1;
+                continue;
+            }
+            match read_regular_file(&self.out, &name, remaining) {
+                Ok(Some(data)) => {
+                    remaining -= data.len();
+                    artifacts.files.push(Artifact {
+                        name: name.to_string_lossy().into_owned(),
+                        data,
+                    });
+                }
+                // not a regular file
+                Ok(None) => (),
+                Err(e) => {
+                    log::debug!("artifact {name:?} omitted: {e}");
+                    artifacts.omitted += 1;
+                }
+            }
+        }
+        artifacts
+    }
+
+    /// Path of the compiler output inside the machine
+    pub fn compile_log_guest_path(&self) -> PathBuf {
+        self.guest_path.join(COMPILE_LOG)
+    }
+
+    /// Path of the file created once the compiler succeeded, inside the machine
+    pub fn compile_ok_guest_path(&self) -> PathBuf {
+        self.guest_path.join(COMPILE_OK)
+    }
+
+    /// Reads the compiler output, `None` if nothing was compiled
+    ///
+    /// The compiler failed unless the marker of its success exists.
+    pub fn diagnostics(&self) -> Option<Diagnostics> {
+        let (output, omitted) = match read_regular_head(&self.dir, COMPILE_LOG, DIAGNOSTICS_LIMIT) {
+            Ok(Some(read)) => read,
+            Ok(None) => return None,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("failed to read compile log of {}: {e}", self.name);
+                return None;
+            }
+        };
+        let failed = !matches!(
+            open_nofollow(&self.dir, COMPILE_OK).and_then(file_type),
+            Ok(FileType::RegularFile)
+        );
+        Some(Diagnostics {
+            output,
+            omitted,
+            failed,
+        })
+    }
+
+    /// Creates the new file `name` owned by the guest user
+    pub fn create_file(&self, name: &str) -> Result<File, io::Error> {
+        let fd = openat(
+            &self.dir,
+            name,
+            OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )?;
+        fchown(&fd, Some(self.uid), Some(self.gid))?;
+        Ok(File::from_std(fd.into()))
+    }
+}
+
+impl Drop for TaskDir {
+    fn drop(&mut self) {
+        if let Err(e) = remove_all_at(self.tasks.as_fd(), &self.name) {
+            log::warn!("failed to remove task directory {}: {e}", self.name);
+        }
+    }
+}
+
+/// Names in the directory besides `.` and `..`
+pub(crate) fn list_dir(dir: impl AsFd) -> Result<Vec<CString>, Errno> {
+    let mut names = Vec::new();
+    for entry in Dir::read_from(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name != c"." && name != c".." {
+            names.push(CString::from(name));
+        }
+    }
+    Ok(names)
+}
+
+/// Opens `name` in `dir` for reading, symlinks fail with `ELOOP`
+pub(crate) fn open_nofollow<P: rustix::path::Arg>(
+    dir: impl AsFd,
+    name: P,
+) -> Result<OwnedFd, Errno> {
+    // opening a fifo would block without O_NONBLOCK
+    openat(
+        dir,
+        name,
+        OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+}
+
+/// Reads `name` in `dir` if it is a regular file of at most `limit` bytes
+pub(crate) fn read_regular_file(
+    dir: impl AsFd,
+    name: &CStr,
+    limit: usize,
+) -> Result<Option<Vec<u8>>, io::Error> {
+    let fd = match open_nofollow(dir, name) {
+        Ok(fd) => fd,
+        Err(Errno::LOOP) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if file_type(&fd)? != FileType::RegularFile {
+        return Ok(None);
+    }
+    read_file(fd, limit).map(Some)
+}
+
+/// Reads at most `limit` bytes of the regular file `name`, with the number of
+/// bytes left out, `None` if it is not a regular file
+fn read_regular_head(
+    dir: impl AsFd,
+    name: &str,
+    limit: usize,
+) -> Result<Option<(Vec<u8>, usize)>, io::Error> {
+    let fd = match open_nofollow(dir, name) {
+        Ok(fd) => fd,
+        Err(Errno::LOOP) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if file_type(&fd)? != FileType::RegularFile {
+        return Ok(None);
+    }
+    let size = fstat(&fd)?.st_size as usize;
+    let mut data = Vec::new();
+    std::fs::File::from(fd)
+        .take(limit as u64)
+        .read_to_end(&mut data)?;
+    let omitted = size.saturating_sub(data.len());
+    Ok(Some((data, omitted)))
+}
+
+pub(crate) fn file_type(fd: impl AsFd) -> Result<FileType, Errno> {
+    Ok(FileType::from_raw_mode(fstat(fd)?.st_mode))
+}
+
+/// Reads the opened file, failing if it has more than `limit` bytes
+pub(crate) fn read_file(fd: OwnedFd, limit: usize) -> Result<Vec<u8>, io::Error> {
+    let size = fstat(&fd)?.st_size as u64;
+    if size > limit as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "size limit exceeded",
+        ));
+    }
+    let mut data = Vec::with_capacity(size as usize);
+    // the file may grow meanwhile
+    std::fs::File::from(fd)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "size limit exceeded",
+        ));
+    }
+    Ok(data)
+}
+
+/// Removes `name` in `parent` recursively without following symlinks
+///
+/// Directories being emptied are kept on a stack rather than the call stack,
+/// the guest decides how deep the tree is.
+fn remove_all_at<P: rustix::path::Arg + Copy>(parent: BorrowedFd, name: P) -> Result<(), Errno> {
+    let dir = match open_dir_at(parent, name) {
+        Ok(dir) => dir,
+        Err(Errno::LOOP | Errno::NOTDIR) => return unlinkat(parent, name, AtFlags::empty()),
+        Err(e) => return Err(e),
+    };
+    // entries are collected first, removing them would disturb the iteration
+    let entries = list_dir(&dir)?.into_iter();
+    // directories with the entries left in them and their names in the parent
+    let mut stack: Vec<(OwnedFd, _, Option<CString>)> = vec![(dir, entries, None)];
+    while let Some((dir, entries, _)) = stack.last_mut() {
+        let Some(entry) = entries.next() else {
+            let (_, _, name) = stack.pop().unwrap();
+            if let (Some(name), Some((parent, _, _))) = (name, stack.last()) {
+                unlinkat(parent, name.as_c_str(), AtFlags::REMOVEDIR)?;
+            }
+            continue;
+        };
+        match open_dir_at(&*dir, entry.as_c_str()) {
+            Ok(child) => {
+                let entries = list_dir(&child)?.into_iter();
+                stack.push((child, entries, Some(entry)));
+            }
+            Err(Errno::LOOP | Errno::NOTDIR) => {
+                unlinkat(&*dir, entry.as_c_str(), AtFlags::empty())?
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unlinkat(parent, name, AtFlags::REMOVEDIR)
+}
@@ -0,0 +1,103 @@
+//! Fixtures shared by the integration tests
+
+#![allow(dead_code)]
+
+use ace_bot::scheduler::Owner;
+use ace_bot::{AceBot, Mode, Options, Request, Role};
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
+
+/// A scratch directory with a home and a directory outside of it
+pub struct Scratch {
+    pub root: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("ace-bot-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("home")).unwrap();
+        fs::create_dir_all(root.join("outside")).unwrap();
+        Self { root }
+    }
+
+    pub fn home(&self) -> PathBuf {
+        self.root.join("home")
+    }
+
+    pub fn outside(&self) -> PathBuf {
+        self.root.join("outside")
+    }
+
+    /// A bot running commands on the host, the guest and the host see the
+    /// home at the same path
+    pub fn ace(&self, options: impl IntoIterator<Item = String>) -> AceBot {
+        let home = self.home();
+        let mut args = vec![
+            "ace-bot".to_string(),
+            format!("--user-mode-user={}", current_user()),
+            format!("--user-mode-group={}", current_group()),
+            format!("--user-guest-home={}", home.display()),
+            format!("--user-host-home={}", home.display()),
+            "--machine=machine".to_string(),
+            "--machine-unit=machine.service".to_string(),
+            format!("--reset-indicator={}", self.root.join("reset").display()),
+            "--executor=local".to_string(),
+        ];
+        args.extend(options);
+        AceBot::new(Options::parse_from(args)).unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// A request of a member in the chat `chat`
+pub fn request(mode: Mode, text: &str) -> Request {
+    Request {
+        owner: Owner {
+            chat: "chat".to_string(),
+            user: "user".to_string(),
+        },
+        mode,
+        text: text.to_string(),
+        background: false,
+        timeout: None,
+        offline: false,
+        engine: None,
+        role: Role::Member,
+        stdin: None,
+        in_inbox: false,
+    }
+}
+
+/// Options lifting the limits of `mode` the local executor refuses
+pub fn lift_limits(mode: &str) -> Vec<String> {
+    [
+        "memory-max=infinity",
+        "cpu-quota=infinity",
+        "tasks-max=infinity",
+        "offline=no",
+    ]
+    .iter()
+    .map(|limit| format!("--limit={mode}.{limit}"))
+    .collect()
+}
+
+pub fn current_user() -> String {
+    users::get_current_username()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn current_group() -> String {
+    users::get_current_groupname()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+}
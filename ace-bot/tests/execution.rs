@@ -0,0 +1,91 @@
+use ace_bot::AceError;
+use ace_bot::execution::{Event, ExecutionResult, Exit, OutputCollector};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+fn exit() -> Event {
+    Event::Exit(Exit {
+        status: ExitStatus::from_raw(0),
+        duration: Duration::from_secs(1),
+        usage: Default::default(),
+        cancelled: false,
+        artifacts: Default::default(),
+        diagnostics: None,
+    })
+}
+
+/// Collects `stdout` pushed in chunks of `chunk` bytes
+fn collect(limit: usize, stdout: &[u8], chunk: usize) -> ExecutionResult {
+    let mut collector = OutputCollector::new(limit, 0);
+    for data in stdout.chunks(chunk) {
+        collector.push(Event::Stdout(data.to_vec()));
+    }
+    collector.push(exit());
+    collector.into_result().unwrap()
+}
+
+fn numbers(n: u8) -> Vec<u8> {
+    (0..n).collect()
+}
+
+#[test]
+fn keeps_output_within_the_limit() {
+    let result = collect(10, &numbers(10), 3);
+    assert_eq!(result.stdout, numbers(10));
+    assert_eq!(result.stdout_omitted, 0);
+    assert!(!result.is_truncated());
+}
+
+#[test]
+fn keeps_head_and_tail_of_long_output() {
+    for chunk in [1, 3, 7, 100] {
+        let result = collect(10, &numbers(100), chunk);
+        let expected: Vec<u8> = (0..5).chain(95..100).collect();
+        assert_eq!(result.stdout, expected, "chunks of {chunk}");
+        assert_eq!(result.stdout_omitted, 90, "chunks of {chunk}");
+        assert!(result.is_truncated());
+    }
+}
+
+#[test]
+fn gives_the_odd_byte_to_the_tail() {
+    let result = collect(5, &numbers(20), 4);
+    let expected: Vec<u8> = (0..2).chain(17..20).collect();
+    assert_eq!(result.stdout, expected);
+    assert_eq!(result.stdout_omitted, 15);
+}
+
+#[test]
+fn bounds_streams_separately() {
+    let mut collector = OutputCollector::new(4, 0);
+    collector.push(Event::Stdout(b"abcdefgh".to_vec()));
+    collector.push(Event::Stderr(b"12".to_vec()));
+    collector.push(exit());
+    let result = collector.into_result().unwrap();
+    assert_eq!(result.stdout, b"abgh");
+    assert_eq!(result.stdout_omitted, 4);
+    assert_eq!(result.stderr, b"12");
+    assert_eq!(result.stderr_omitted, 0);
+}
+
+#[test]
+fn keeps_the_tail_of_interleaved_output_live() {
+    let mut collector = OutputCollector::new(100, 6);
+    assert!(!collector.take_changed());
+    collector.push(Event::Stdout(b"out1\n".to_vec()));
+    collector.push(Event::Stderr(b"err1\n".to_vec()));
+    assert!(collector.take_changed());
+    assert!(!collector.take_changed());
+    assert_eq!(collector.live(), "\nerr1\n");
+}
+
+#[test]
+fn requires_an_exit_status() {
+    let mut collector = OutputCollector::new(10, 0);
+    collector.push(Event::Stdout(b"out".to_vec()));
+    assert!(matches!(
+        collector.into_result(),
+        Err(AceError::MissingExitStatus)
+    ));
+}
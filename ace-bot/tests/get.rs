@@ -0,0 +1,113 @@
+mod common;
+
+use ace_bot::{AceBot, AceError};
+use common::Scratch;
+use std::fs;
+use std::io::Cursor;
+use std::os::unix::fs::symlink;
+
+/// A home with a file in it and a secret outside of it
+fn home_with_file(name: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+    fs::write(scratch.home().join("file"), b"file").unwrap();
+    fs::write(scratch.outside().join("secret"), b"secret").unwrap();
+    scratch
+}
+
+async fn get(ace: &AceBot, path: &str) -> Result<Vec<u8>, AceError> {
+    ace.get("chat", path, false)
+        .await
+        .map(|artifact| artifact.data)
+}
+
+#[tokio::test]
+async fn reads_files_in_home() {
+    let scratch = home_with_file("home");
+    let ace = scratch.ace([]);
+    assert_eq!(get(&ace, "file").await.unwrap(), b"file");
+    assert_eq!(get(&ace, "./file").await.unwrap(), b"file");
+    assert_eq!(get(&ace, "~/file").await.unwrap(), b"file");
+    let absolute = scratch.home().join("file");
+    assert_eq!(
+        get(&ace, &absolute.to_string_lossy()).await.unwrap(),
+        b"file"
+    );
+}
+
+#[tokio::test]
+async fn rejects_parent_components() {
+    let scratch = home_with_file("parent");
+    let ace = scratch.ace([]);
+    for path in [
+        "..",
+        "../outside/secret",
+        "dir/../file",
+        "~/..",
+        "~/../outside/secret",
+    ] {
+        assert!(
+            matches!(get(&ace, path).await, Err(AceError::OutsideHome(_))),
+            "{path}"
+        );
+    }
+    let escaping = scratch.home().join("../outside/secret");
+    assert!(matches!(
+        get(&ace, &escaping.to_string_lossy()).await,
+        Err(AceError::OutsideHome(_))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_absolute_paths_outside_of_home() {
+    let scratch = home_with_file("absolute");
+    let ace = scratch.ace([]);
+    let secret = scratch.outside().join("secret");
+    for path in [secret.to_string_lossy().into_owned(), "/".to_string()] {
+        assert!(
+            matches!(get(&ace, &path).await, Err(AceError::OutsideHome(_))),
+            "{path}"
+        );
+    }
+    // a sibling sharing the name of the home as a prefix
+    let sibling = scratch.root.join("home-sibling");
+    fs::create_dir(&sibling).unwrap();
+    fs::write(sibling.join("file"), b"sibling").unwrap();
+    assert!(matches!(
+        get(&ace, &sibling.join("file").to_string_lossy()).await,
+        Err(AceError::OutsideHome(_))
+    ));
+}
+
+#[tokio::test]
+async fn does_not_follow_symlinks() {
+    let scratch = home_with_file("symlink");
+    symlink(
+        scratch.outside().join("secret"),
+        scratch.home().join("link"),
+    )
+    .unwrap();
+    symlink(scratch.outside(), scratch.home().join("dir")).unwrap();
+    let ace = scratch.ace([]);
+    for path in ["link", "~/link", "dir/secret"] {
+        assert!(get(&ace, path).await.is_err(), "{path}");
+    }
+}
+
+#[tokio::test]
+async fn zips_nested_directories() {
+    let scratch = home_with_file("zip");
+    fs::create_dir_all(scratch.home().join("dir/nested")).unwrap();
+    fs::write(scratch.home().join("dir/nested/file"), b"file").unwrap();
+    symlink(scratch.outside(), scratch.home().join("dir/link")).unwrap();
+    let ace = scratch.ace([]);
+    assert!(matches!(
+        ace.get("chat", "dir", false).await,
+        Err(AceError::IsDirectory(_))
+    ));
+    let artifact = ace.get("chat", "dir", true).await.unwrap();
+    assert_eq!(artifact.name, "dir.zip");
+    let archive = zip::ZipArchive::new(Cursor::new(artifact.data)).unwrap();
+    let mut names: Vec<_> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["nested/", "nested/file"]);
+}
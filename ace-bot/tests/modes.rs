@@ -0,0 +1,66 @@
+use ace_bot::Mode;
+use ace_bot::modes::{self, ModeSpec};
+use regex::{Regex, RegexBuilder};
+use std::fs;
+
+fn load(name: &str, text: &str) -> Result<Vec<Mode>, ace_bot::AceError> {
+    let path = std::env::temp_dir().join(format!("ace-bot-modes-{}-{name}", std::process::id()));
+    fs::write(&path, text).unwrap();
+    let modes = modes::load(std::slice::from_ref(&path));
+    let _ = fs::remove_file(&path);
+    modes
+}
+
+fn spec(modes: &[Mode], name: &str) -> &'static ModeSpec {
+    match modes.iter().find(|mode| mode.to_string() == name) {
+        Some(Mode::Custom(spec)) => spec,
+        _ => panic!("mode {name} not found"),
+    }
+}
+
+fn pattern(spec: &ModeSpec, prefix: &str) -> Regex {
+    RegexBuilder::new(&spec.command_pattern(prefix))
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+}
+
+fn text<'a>(pattern: &Regex, message: &'a str) -> Option<&'a str> {
+    Some(pattern.captures(message)?.get(2).unwrap().as_str())
+}
+
+#[test]
+fn commands_end_at_a_word_boundary() {
+    let modes = modes::load(&[]).unwrap();
+    let typst = pattern(spec(&modes, "typst"), "/");
+    assert_eq!(text(&typst, "/typst $x$"), Some("$x$"));
+    assert_eq!(text(&typst, "/typst@ace_bot $x$"), Some("$x$"));
+    assert_eq!(text(&typst, "/typst\n= Title"), Some("= Title"));
+    assert_eq!(text(&typst, "/typst"), Some(""));
+    assert_eq!(text(&typst, "/typstx $x$"), None);
+    assert_eq!(text(&typst, "!typst $x$"), None);
+    let typst = pattern(spec(&modes, "typst"), "!");
+    assert_eq!(text(&typst, "!typst@ace_bot $x$"), Some("$x$"));
+    assert_eq!(text(&typst, "!typsty"), None);
+}
+
+#[test]
+fn rejects_commands_taken_by_the_frontends() {
+    let source = "source = \"x\"\nbuild = \"true\"\n";
+    assert!(load("reserved", &format!("[get]\n{source}")).is_err());
+    assert!(load("prefixed", &format!("[getter]\n{source}")).is_err());
+    assert!(load("command", &format!("[x]\ncommand = \"users\"\n{source}")).is_err());
+    assert!(load("word", &format!("[ok]\n{source}")).is_ok());
+}
+
+#[test]
+fn one_letter_commands_do_not_take_longer_ones() {
+    let modes = modes::load(&[]).unwrap();
+    let c = pattern(spec(&modes, "c"), "/");
+    assert_eq!(text(&c, "/c int main(void) {}"), Some("int main(void) {}"));
+    assert_eq!(text(&c, "/c\nint main(void) {}"), Some("int main(void) {}"));
+    assert_eq!(text(&c, "/clear"), None);
+    assert_eq!(text(&c, "/config x"), None);
+    let c = pattern(spec(&modes, "c"), "!");
+    assert_eq!(text(&c, "!cl x"), None);
+}
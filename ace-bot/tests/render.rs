@@ -0,0 +1,76 @@
+//! Built-in modes run by the local executor, each test is skipped unless the
+//! programs of its mode are available
+
+mod common;
+
+use ace_bot::execution::ExecutionResult;
+use common::{Scratch, lift_limits, request};
+use std::process::{Command, Stdio};
+
+/// Whether `program` runs with `args` and succeeds
+fn runs(program: &str, args: &[&str]) -> bool {
+    Command::new(program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+async fn render(name: &str, text: &str) -> ExecutionResult {
+    let scratch = Scratch::new(name);
+    let mut options = lift_limits(name);
+    options.push("--shell=bash".to_string());
+    let ace = scratch.ace(options);
+    let mode = *ace
+        .modes()
+        .iter()
+        .find(|mode| mode.to_string() == name)
+        .unwrap();
+    let execution = ace.spawn(&request(mode, text)).await.unwrap();
+    execution.result().await.unwrap()
+}
+
+fn is_svg(result: &ExecutionResult) -> bool {
+    String::from_utf8_lossy(&result.stdout).contains("<svg")
+}
+
+#[tokio::test]
+async fn evaluates_nix() {
+    // `nix eval` needs the nix-command feature
+    if !runs("nix", &["eval", "--expr", "1"]) {
+        eprintln!("skipped, nix eval is not available");
+        return;
+    }
+    let result = render("nix", "1 + 1").await;
+    assert!(result.status.success(), "{result:?}");
+    assert_eq!(result.stdout, b"2\n");
+    let result = render("nix", "1 +").await;
+    assert!(!result.status.success());
+}
+
+#[tokio::test]
+async fn renders_typst() {
+    if !runs("typst", &["--version"]) {
+        eprintln!("skipped, typst is not available");
+        return;
+    }
+    let result = render("typst", "$ a^2 + b^2 = c^2 $").await;
+    assert!(result.status.success(), "{result:?}");
+    assert!(is_svg(&result));
+    let result = render("typst", "#undefined").await;
+    assert!(!result.status.success());
+}
+
+#[tokio::test]
+async fn renders_xelatex() {
+    if !runs("xelatex", &["--version"]) || !runs("dvisvgm", &["--version"]) {
+        eprintln!("skipped, xelatex or dvisvgm is not available");
+        return;
+    }
+    let result = render("xelatex", r"$a^2 + b^2 = c^2$").await;
+    assert!(result.status.success(), "{result:?}");
+    assert!(is_svg(&result));
+    let result = render("xelatex", r"\undefined").await;
+    assert!(!result.status.success());
+}
@@ -0,0 +1,94 @@
+use ace_bot::scheduler::{Limits, Owner, Queued, Scheduler};
+use std::sync::Arc;
+
+fn scheduler(global: usize, per_chat: usize, per_user: usize) -> Arc<Scheduler> {
+    Arc::new(Scheduler::new(Limits {
+        global,
+        per_chat,
+        per_user,
+    }))
+}
+
+fn owner(chat: &str, user: &str) -> Owner {
+    Owner {
+        chat: chat.to_string(),
+        user: user.to_string(),
+    }
+}
+
+fn position(queued: &Queued) -> usize {
+    *queued.position().borrow()
+}
+
+fn positions(queued: &[&Queued]) -> Vec<usize> {
+    queued.iter().map(|q| position(q)).collect()
+}
+
+#[tokio::test]
+async fn starts_jobs_in_order_of_arrival() {
+    let scheduler = scheduler(1, 10, 10);
+    let a = scheduler.enqueue(owner("a", "a"));
+    let b = scheduler.enqueue(owner("b", "b"));
+    let c = scheduler.enqueue(owner("c", "c"));
+    assert_eq!(positions(&[&a, &b, &c]), [0, 1, 2]);
+    let permit = a.wait().await;
+    assert_eq!(positions(&[&b, &c]), [1, 2]);
+    drop(permit);
+    assert_eq!(positions(&[&b, &c]), [0, 1]);
+    let permit = b.wait().await;
+    drop(permit);
+    assert_eq!(position(&c), 0);
+    c.wait().await;
+}
+
+#[tokio::test]
+async fn limits_jobs_globally() {
+    let scheduler = scheduler(2, 10, 10);
+    let a = scheduler.enqueue(owner("a", "a"));
+    let b = scheduler.enqueue(owner("b", "b"));
+    let c = scheduler.enqueue(owner("c", "c"));
+    assert_eq!(positions(&[&a, &b, &c]), [0, 0, 1]);
+    let permit = a.wait().await;
+    let _b = b.wait().await;
+    assert_eq!(position(&c), 1);
+    drop(permit);
+    assert_eq!(position(&c), 0);
+}
+
+#[tokio::test]
+async fn limits_jobs_per_chat() {
+    let scheduler = scheduler(10, 1, 10);
+    let a = scheduler.enqueue(owner("chat", "a"));
+    let b = scheduler.enqueue(owner("chat", "b"));
+    // jobs of other chats pass the waiting one
+    let c = scheduler.enqueue(owner("other", "c"));
+    assert_eq!(positions(&[&a, &b, &c]), [0, 1, 0]);
+    let permit = a.wait().await;
+    drop(permit);
+    assert_eq!(position(&b), 0);
+}
+
+#[tokio::test]
+async fn limits_jobs_per_user() {
+    let scheduler = scheduler(10, 10, 1);
+    let a = scheduler.enqueue(owner("a", "user"));
+    let b = scheduler.enqueue(owner("b", "user"));
+    let c = scheduler.enqueue(owner("c", "other"));
+    assert_eq!(positions(&[&a, &b, &c]), [0, 1, 0]);
+    let permit = a.wait().await;
+    drop(permit);
+    assert_eq!(position(&b), 0);
+}
+
+#[tokio::test]
+async fn leaving_the_queue_moves_others_up() {
+    let scheduler = scheduler(1, 10, 10);
+    let a = scheduler.enqueue(owner("a", "a"));
+    let b = scheduler.enqueue(owner("b", "b"));
+    let c = scheduler.enqueue(owner("c", "c"));
+    drop(b);
+    assert_eq!(positions(&[&a, &c]), [0, 1]);
+    // a job which may start but never waits gives up its slot as well
+    drop(a);
+    assert_eq!(position(&c), 0);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use ace_bot::execution::ExecutionResult;
+use ace_bot::{AceBot, Mode};
+use common::{Scratch, lift_limits, request};
+use futures::StreamExt;
+use std::time::{Duration, Instant};
+
+fn ace(scratch: &Scratch) -> AceBot {
+    let mut options = lift_limits("non-root");
+    options.push("--shell=bash".to_string());
+    scratch.ace(options)
+}
+
+async fn run(ace: &AceBot, text: &str) -> ExecutionResult {
+    let request = request(Mode::NonRoot, text);
+    ace.run_in_session(&request)
+        .unwrap()
+        .result()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn keeps_state_between_commands() {
+    let scratch = Scratch::new("session-state");
+    let ace = ace(&scratch);
+    let owner = request(Mode::NonRoot, "").owner;
+    ace.start_session(&owner).await.unwrap();
+    assert!(run(&ace, "X=42; cd /").await.status.success());
+    let result = run(&ace, "echo $X; pwd; false").await;
+    assert_eq!(result.stdout, b"42\n/\n");
+    assert!(!result.status.success());
+}
+
+#[tokio::test]
+async fn cancels_commands_as_jobs() {
+    let scratch = Scratch::new("session-cancel");
+    let ace = ace(&scratch);
+    let owner = request(Mode::NonRoot, "").owner;
+    ace.start_session(&owner).await.unwrap();
+    let start = Instant::now();
+    let mut execution = ace
+        .run_in_session(&request(Mode::NonRoot, "echo started; sleep 30"))
+        .unwrap();
+    let mut collector = execution.collector(0);
+    collector.push(execution.next().await.unwrap().unwrap());
+    // an interrupt before the shell forks `sleep` would only stop `echo`
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let jobs = ace.jobs(Some(&owner.chat));
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].id, execution.id());
+    ace.cancel(execution.id(), &owner, false).await.unwrap();
+    while let Some(event) = execution.next().await {
+        collector.push(event.unwrap());
+    }
+    let result = collector.into_result().unwrap();
+    assert!(result.cancelled);
+    assert_eq!(result.stdout, b"started\n");
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(ace.jobs(Some(&owner.chat)).is_empty());
+    // the shell outlives the command
+    assert!(ace.has_session(&owner.chat));
+    // the shell prints a line break after the interrupt
+    assert!(run(&ace, "echo after").await.stdout.ends_with(b"after\n"));
+}
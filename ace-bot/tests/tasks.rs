@@ -0,0 +1,257 @@
+mod common;
+
+use ace_bot::tasks::TaskDirs;
+use common::Scratch;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+fn task_dirs(scratch: &Scratch) -> TaskDirs {
+    TaskDirs::new(
+        scratch.home(),
+        PathBuf::from("/guest"),
+        rustix::process::getuid().as_raw(),
+        rustix::process::getgid().as_raw(),
+        1024,
+    )
+}
+
+fn is_real_dir(path: &Path) -> bool {
+    fs::symlink_metadata(path).unwrap().file_type().is_dir()
+}
+
+#[test]
+fn creates_files_in_task_dir() {
+    let scratch = Scratch::new("create");
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    drop(task_dir.create_file("main.typ").unwrap());
+    let name = task_dir.guest_path().file_name().unwrap();
+    assert_eq!(
+        task_dir.guest_path(),
+        Path::new("/guest/.ace-bot/tasks").join(name)
+    );
+    let host_path = scratch.home().join(".ace-bot/tasks").join(name);
+    assert!(host_path.join("main.typ").is_file());
+    drop(task_dir);
+    assert!(!host_path.exists());
+}
+
+#[test]
+fn replaces_symlinked_ace_bot_dir() {
+    let scratch = Scratch::new("ace-bot-symlink");
+    symlink(scratch.outside(), scratch.home().join(".ace-bot")).unwrap();
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    drop(task_dir.create_file("main.typ").unwrap());
+    assert!(is_real_dir(&scratch.home().join(".ace-bot")));
+    assert_eq!(fs::read_dir(scratch.outside()).unwrap().count(), 0);
+}
+
+#[test]
+fn replaces_symlinked_tasks_dir() {
+    let scratch = Scratch::new("tasks-symlink");
+    fs::create_dir(scratch.home().join(".ace-bot")).unwrap();
+    symlink(scratch.outside(), scratch.home().join(".ace-bot/tasks")).unwrap();
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    drop(task_dir.create_file("main.typ").unwrap());
+    assert!(is_real_dir(&scratch.home().join(".ace-bot/tasks")));
+    assert_eq!(fs::read_dir(scratch.outside()).unwrap().count(), 0);
+}
+
+#[test]
+fn does_not_create_files_through_symlinks() {
+    let scratch = Scratch::new("file-symlink");
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    let name = task_dir.guest_path().file_name().unwrap();
+    let host_path = scratch.home().join(".ace-bot/tasks").join(name);
+    let target = scratch.outside().join("target");
+    symlink(&target, host_path.join("main.typ")).unwrap();
+    assert!(task_dir.create_file("main.typ").is_err());
+    assert!(!target.exists());
+}
+
+#[test]
+fn removal_does_not_follow_symlinks() {
+    let scratch = Scratch::new("remove-symlink");
+    fs::write(scratch.outside().join("kept"), "kept").unwrap();
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    let name = task_dir.guest_path().file_name().unwrap();
+    let host_path = scratch.home().join(".ace-bot/tasks").join(name);
+    fs::create_dir(host_path.join("nested")).unwrap();
+    symlink(scratch.outside(), host_path.join("nested/link")).unwrap();
+    drop(task_dir);
+    assert!(!host_path.exists());
+    assert!(scratch.outside().join("kept").is_file());
+}
+
+#[test]
+fn removes_deep_trees_without_recursion() {
+    let scratch = Scratch::new("remove-deep");
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    let name = task_dir.guest_path().file_name().unwrap();
+    let host_path = scratch.home().join(".ace-bot/tasks").join(name);
+    let deep: PathBuf = std::iter::repeat_n("d", 1500).collect();
+    fs::create_dir_all(host_path.join(&deep)).unwrap();
+    fs::write(host_path.join(&deep).join("file"), "file").unwrap();
+    // removing the tree one frame per directory would overflow this stack
+    std::thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || drop(task_dir))
+        .unwrap()
+        .join()
+        .unwrap();
+    assert!(!host_path.exists());
+}
+
+#[test]
+fn collects_regular_files_as_artifacts() {
+    let scratch = Scratch::new("artifacts");
+    fs::write(scratch.outside().join("secret"), "secret").unwrap();
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    let name = task_dir.guest_path().file_name().unwrap();
+    let out = scratch.home().join(".ace-bot/tasks").join(name).join("out");
+    assert_eq!(task_dir.out_guest_path(), task_dir.guest_path().join("out"));
+    fs::write(out.join("b.csv"), "1,2").unwrap();
+    fs::write(out.join("a.txt"), "a").unwrap();
+    fs::write(out.join("large"), vec![0; 2048]).unwrap();
+    fs::create_dir(out.join("nested")).unwrap();
+    symlink(scratch.outside().join("secret"), out.join("link")).unwrap();
+    rustix::fs::mknodat(
+        rustix::fs::CWD,
+        out.join("fifo"),
+        rustix::fs::FileType::Fifo,
+        rustix::fs::Mode::from_bits_truncate(0o644),
+        0,
+    )
+    .unwrap();
+    let artifacts = task_dir.artifacts();
+    let names: Vec<_> = artifacts.files.iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(names, ["a.txt", "b.csv"]);
+    assert_eq!(artifacts.files[1].data, b"1,2");
+    assert_eq!(artifacts.omitted, 1);
+}
+
+#[test]
+fn reads_compiler_diagnostics() {
+    let scratch = Scratch::new("diagnostics");
+    fs::write(scratch.outside().join("secret"), "secret").unwrap();
+    let task_dir = task_dirs(&scratch).create().unwrap();
+    let name = task_dir.guest_path().file_name().unwrap();
+    let dir = scratch.home().join(".ace-bot/tasks").join(name);
+    assert!(task_dir.diagnostics().is_none());
+    assert_eq!(
+        task_dir.compile_log_guest_path(),
+        task_dir.guest_path().join("compile.log")
+    );
+    fs::write(dir.join("compile.log"), "main.c:1:1: error").unwrap();
+    let diagnostics = task_dir.diagnostics().unwrap();
+    assert_eq!(diagnostics.output, b"main.c:1:1: error");
+    assert!(diagnostics.failed);
+    fs::write(dir.join("compile.ok"), "").unwrap();
+    assert!(!task_dir.diagnostics().unwrap().failed);
+    fs::remove_file(dir.join("compile.log")).unwrap();
+    symlink(scratch.outside().join("secret"), dir.join("compile.log")).unwrap();
+    assert!(task_dir.diagnostics().is_none());
+}
+
+#[tokio::test]
+async fn saves_uploads_in_nested_dir() {
+    let scratch = Scratch::new("save");
+    let task_dirs = task_dirs(&scratch);
+    let path = task_dirs
+        .save(Path::new("inbox/data"), "data.csv", b"a,b\n")
+        .await
+        .unwrap();
+    assert_eq!(path, Path::new("/guest/inbox/data/data.csv"));
+    let host_path = scratch.home().join("inbox/data/data.csv");
+    assert_eq!(fs::read(&host_path).unwrap(), b"a,b\n");
+    task_dirs
+        .save(Path::new("inbox/data"), "data.csv", b"c,d\n")
+        .await
+        .unwrap();
+    assert_eq!(fs::read(&host_path).unwrap(), b"c,d\n");
+    // no temporary files are left behind
+    assert_eq!(
+        fs::read_dir(scratch.home().join("inbox/data"))
+            .unwrap()
+            .count(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn saves_uploads_without_following_symlinks() {
+    let scratch = Scratch::new("save-symlink");
+    fs::write(scratch.outside().join("target"), b"kept").unwrap();
+    symlink(scratch.outside(), scratch.home().join("inbox")).unwrap();
+    let task_dirs = task_dirs(&scratch);
+    assert!(
+        task_dirs
+            .save(Path::new("inbox"), "target", b"data")
+            .await
+            .is_err()
+    );
+    assert!(
+        fs::symlink_metadata(scratch.home().join("inbox"))
+            .unwrap()
+            .is_symlink()
+    );
+    fs::create_dir(scratch.home().join("link")).unwrap();
+    symlink(
+        scratch.outside().join("target"),
+        scratch.home().join("link/target"),
+    )
+    .unwrap();
+    task_dirs
+        .save(Path::new("link"), "target", b"data")
+        .await
+        .unwrap();
+    let replaced = scratch.home().join("link/target");
+    assert!(fs::symlink_metadata(&replaced).unwrap().is_file());
+    assert_eq!(fs::read(scratch.outside().join("target")).unwrap(), b"kept");
+}
+
+#[tokio::test]
+async fn keeps_files_in_the_way_of_the_inbox() {
+    let scratch = Scratch::new("save-file");
+    fs::write(scratch.home().join("inbox"), b"kept").unwrap();
+    let task_dirs = task_dirs(&scratch);
+    assert!(
+        task_dirs
+            .save(Path::new("inbox"), "upload", b"data")
+            .await
+            .is_err()
+    );
+    assert_eq!(fs::read(scratch.home().join("inbox")).unwrap(), b"kept");
+}
+
+#[tokio::test]
+async fn rejects_inbox_outside_of_home() {
+    let scratch = Scratch::new("save-parent");
+    let task_dirs = task_dirs(&scratch);
+    assert!(
+        task_dirs
+            .save(Path::new("../outside"), "data", b"data")
+            .await
+            .is_err()
+    );
+    assert_eq!(fs::read_dir(scratch.outside()).unwrap().count(), 0);
+}
+
+#[test]
+fn keeps_files_in_the_way_of_chat_workdirs() {
+    let scratch = Scratch::new("workdir-file");
+    fs::create_dir(scratch.home().join("chats")).unwrap();
+    fs::write(scratch.home().join("chats/1"), b"kept").unwrap();
+    symlink(scratch.outside(), scratch.home().join("chats/2")).unwrap();
+    let task_dirs = task_dirs(&scratch);
+    assert!(task_dirs.create_dir_all(Path::new("chats/1")).is_err());
+    assert!(task_dirs.create_dir_all(Path::new("chats/2")).is_err());
+    assert_eq!(fs::read(scratch.home().join("chats/1")).unwrap(), b"kept");
+    assert!(
+        fs::symlink_metadata(scratch.home().join("chats/2"))
+            .unwrap()
+            .is_symlink()
+    );
+    task_dirs.create_dir_all(Path::new("chats/3")).unwrap();
+    assert!(is_real_dir(&scratch.home().join("chats/3")));
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use ace_bot::{AceError, Mode, Request};
+use common::{Scratch, lift_limits};
+
+fn request(text: &str, in_inbox: bool) -> Request {
+    Request {
+        in_inbox,
+        ..common::request(Mode::NonRoot, text)
+    }
+}
+
+#[tokio::test]
+async fn saves_files_to_the_inbox() {
+    let scratch = Scratch::new("upload");
+    let ace = scratch.ace([]);
+    let path = ace.upload("chat", "data.csv", b"a,1\n").await.unwrap();
+    assert_eq!(path, scratch.home().join("inbox/data.csv"));
+    let saved = std::fs::read(scratch.home().join("inbox/data.csv")).unwrap();
+    assert_eq!(saved, b"a,1\n");
+}
+
+#[tokio::test]
+async fn runs_captions_in_the_inbox() {
+    let scratch = Scratch::new("caption");
+    let mut options = lift_limits("non-root");
+    options.push("--shell=bash".to_string());
+    let ace = scratch.ace(options);
+    ace.upload("chat", "data.csv", b"a,1\nb,2\nc,3\n")
+        .await
+        .unwrap();
+    // a caption of `/user wc -l data.csv`
+    let caption = ace.spawn(&request("wc -l data.csv", true)).await.unwrap();
+    let result = caption.result().await.unwrap();
+    assert_eq!(result.stdout, b"3 data.csv\n");
+    // other commands still run in the working directory of the chat
+    let command = ace.spawn(&request("pwd", false)).await.unwrap();
+    let result = command.result().await.unwrap();
+    let pwd = format!("{}\n", scratch.home().display());
+    assert_eq!(result.stdout, pwd.as_bytes());
+}
+
+#[tokio::test]
+async fn refuses_limits_the_local_executor_does_not_enforce() {
+    let scratch = Scratch::new("limits");
+    let ace = scratch.ace([]);
+    let result = ace.spawn(&request("true", false)).await;
+    assert!(matches!(result, Err(AceError::UnenforcedLimit(_))));
+}
@@ -67,6 +67,26 @@ let
           };
         }
       )
+      (
+        { ... }:
+        {
+          # runs commands for the bot, see `--agent-socket`
+          systemd.services."ace-bot-agent" = {
+            script = ''
+              exec ${pkgs.ace-bot}/bin/ace-bot-agent --socket=/run/ace-bot-agent/agent.sock
+            '';
+            serviceConfig = {
+              Restart = "always";
+              # resource limits of commands are applied in child cgroups
+              Delegate = "yes";
+            };
+            environment = {
+              "RUST_LOG" = cfg.rustLog;
+            };
+            wantedBy = [ "multi-user.target" ];
+          };
+        }
+      )
       (
         { ... }:
         {
@@ -114,6 +134,7 @@ let
     PrivateUsersOwnership=map
     BindUser=ace-bot
     BindReadOnly=${envToplevelClosureInfo}/registration:/nix/initial-registration:idmap
+    Bind=/run/ace-bot-agent:/run/ace-bot-agent:idmap
 
     [Network]
   '';
@@ -127,9 +148,14 @@ let
       echo "BindReadOnly=$store_path:$store_path:idmap" >>"$out"
     done
   '';
+  modesFormat = pkgs.formats.toml { };
+  modesFile = modesFormat.generate "ace-bot-modes.toml" cfg.modes;
   commonBotOptions = ''
     --shell="${lib.getExe cfg.shell}" \
     --timeout="${cfg.timeout}" \
+    --background-timeout="${cfg.backgroundTimeout}" \
+    --max-timeout="${cfg.maxTimeout}" \
+    --manager-max-timeout="${cfg.managerMaxTimeout}" \
     --machine="ace-bot" \
     --reset-indicator="/var/lib/ace-bot/reset" \
     --machine-unit="systemd-nspawn@ace-bot.service" \
@@ -137,6 +163,10 @@ let
     --user-mode-group="ace-bot" \
     --user-guest-home="/run/host/home/ace-bot" \
     --user-host-home="${config.users.users.ace-bot.home}" \
+    --agent-socket="/run/ace-bot-agent/agent.sock" \
+    --inbox="${cfg.inbox}" \
+    ${lib.optionalString (cfg.chatWorkdirs != null) ''--chat-workdirs="${cfg.chatWorkdirs}"''} \
+    ${lib.optionalString (cfg.modes != { }) ''--modes="${modesFile}"''} \
     ${lib.escapeShellArgs cfg.extraOptions}'';
 in
 {
@@ -161,6 +191,31 @@ in
       type = with lib.types; nullOr str;
       default = "60";
     };
+    backgroundTimeout = lib.mkOption {
+      type = with lib.types; nullOr str;
+      default = "3600";
+    };
+    maxTimeout = lib.mkOption {
+      type = with lib.types; nullOr str;
+      default = "300";
+    };
+    managerMaxTimeout = lib.mkOption {
+      type = with lib.types; nullOr str;
+      default = "3600";
+    };
+    chatWorkdirs = lib.mkOption {
+      type = with lib.types; nullOr str;
+      default = null;
+      example = "chats";
+    };
+    inbox = lib.mkOption {
+      type = lib.types.str;
+      default = "inbox";
+    };
+    modes = lib.mkOption {
+      type = modesFormat.type;
+      default = { };
+    };
     shell = lib.mkOption {
       type = with lib.types; package;
       default = pkgs.bashInteractive;
@@ -239,6 +294,13 @@ in
   config = lib.mkIf (cfg.enable) (
     lib.mkMerge [
       {
+        assertions = [
+          {
+            # transient units of commands are started with `AddRef`
+            assertion = lib.versionAtLeast envConfiguration.config.systemd.package.version "254";
+            message = "ace-bot needs systemd 254 or newer in its container";
+          }
+        ];
         users.users.ace-bot = {
           isSystemUser = true;
           home = "/var/lib/ace-bot/mount/disk/home";
@@ -257,6 +319,9 @@ in
             LimitNProc = lib.mkDefault 10240;
           };
         };
+        systemd.tmpfiles.rules = [
+          "d /run/ace-bot-agent 0700 root root -"
+        ];
         systemd.targets.machines.wants = [ "systemd-nspawn@ace-bot.service" ];
         systemd.services."systemd-nspawn@ace-bot" = {
           overrideStrategy = "asDropin";